@@ -0,0 +1,132 @@
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Data, Query};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use crate::database::{AuditEntry, DatabaseAccess, LoginInfo};
+use crate::result;
+
+const PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 500;
+// rows per CSV export, further ones are exported with `page`
+const EXPORT_PAGE: i64 = 10_000;
+
+const CSV_HEADER: &str = "id,logged_at,actor,ip,action,entity,entity_id,before,after\r\n";
+
+/// `/audit` filters, every one optional; `from` / `to` are unix timestamps.
+#[derive(Deserialize)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub entity: Option<String>,
+    pub entity_id: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    entries: Vec<AuditEntry>,
+    total: i64,
+    page: i64,
+    per_page: i64,
+}
+
+pub fn client_ip(request: &HttpRequest) -> String {
+    request.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
+
+/// The user making a change and where from, stamped on every audit entry.
+pub struct Auditor {
+    username: String,
+    ip: String,
+}
+
+impl Auditor {
+    pub fn new(login: &LoginInfo, ip: String) -> Self {
+        Auditor { username: login.username.clone(), ip }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn record<T: Serialize>(&self, database: &DatabaseAccess, action: &str, entity: &str, entity_id: &str,
+                                before: Option<&T>, after: Option<&T>) {
+        let json = |data: Option<&T>| data.and_then(|data| serde_json::to_value(data).ok());
+        database.audit(&AuditEntry {
+            id: 0,
+            actor: self.username.clone(),
+            action: action.to_string(),
+            entity: entity.to_string(),
+            entity_id: Some(entity_id.to_string()),
+            before: json(before),
+            after: json(after),
+            ip: self.ip.clone(),
+            logged_at: crate::unix_timestamp(),
+        });
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    // keep spreadsheets from evaluating user supplied text as a formula
+    let value = if value.starts_with(['=', '+', '-', '@']) { format!("'{}", value) } else { value.to_string() };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv_row(entry: &AuditEntry) -> String {
+    let json = |data: &Option<serde_json::Value>| data.as_ref().map(|v| v.to_string()).unwrap_or_default();
+    let fields = [entry.id.to_string(), entry.logged_at.to_string(), entry.actor.clone(), entry.ip.clone(),
+        entry.action.clone(), entry.entity.clone(), entry.entity_id.clone().unwrap_or_default(),
+        json(&entry.before), json(&entry.after)];
+    let mut row = fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",");
+    row.push_str("\r\n");
+    row
+}
+
+/// `/audit`: one page of the audit log for administrators, newest first.
+pub fn list(database: Data<Arc<Mutex<DatabaseAccess>>>, filter: Query<AuditFilter>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let page = filter.page.unwrap_or(1).max(1);
+            let per_page = filter.per_page.unwrap_or(PER_PAGE).clamp(1, MAX_PER_PAGE);
+            let (entries, total) = database.lock().unwrap().find_audit(&filter, per_page, (page - 1) * per_page);
+            return HttpResponse::Ok().json(AuditPage { entries, total, page, per_page });
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+/// `/audit/export`: the entries matching the filters as CSV, at most `EXPORT_PAGE` of them
+/// per `page`; `X-Total-Count` tells how many match in all.
+pub fn export(database: Data<Arc<Mutex<DatabaseAccess>>>, filter: Query<AuditFilter>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let page = filter.page.unwrap_or(1).max(1);
+            let (entries, total) = database.lock().unwrap().find_audit(&filter, EXPORT_PAGE, (page - 1) * EXPORT_PAGE);
+            let mut body = String::from(CSV_HEADER);
+            entries.iter().for_each(|entry| body.push_str(&csv_row(entry)));
+            return HttpResponse::Ok()
+                .content_type("text/csv; charset=utf-8")
+                .header("Content-Disposition", "attachment; filename=\"audit.csv\"")
+                .header("X-Total-Count", total.to_string())
+                .body(body);
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+#[test]
+fn test_csv_field() {
+    assert_eq!(csv_field("admin"), "admin");
+    assert_eq!(csv_field("{\"a\":1,\"b\":2}"), "\"{\"\"a\"\":1,\"\"b\"\":2}\"");
+    assert_eq!(csv_field("=cmd()"), "'=cmd()");
+    assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+}
@@ -0,0 +1,110 @@
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Data, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use crate::config::Config;
+use crate::database::DatabaseAccess;
+use crate::dispatch::{Coordinates, Dispatcher, Isochrone};
+use crate::result;
+
+// travel times drawn unless asked for others, in minutes
+const COVERAGE_MINUTES: [u32; 3] = [5, 10, 15];
+const MAX_COVERAGE_MINUTES: u32 = 120;
+
+#[derive(Deserialize)]
+pub struct CoverageInfo {
+    station: Option<String>,
+    #[serde(default)]
+    combined: bool,
+    minutes: Option<Vec<u32>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Geometry {
+    Polygon { coordinates: Vec<Vec<(f64, f64)>> },
+    MultiLineString { coordinates: Vec<Vec<(f64, f64)>> },
+}
+
+#[derive(Serialize)]
+struct Band {
+    // none for the stations combined
+    station: Option<String>,
+    minutes: u32,
+    // metres driven within the time
+    distance: f64,
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+struct Feature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    properties: Band,
+    geometry: Geometry,
+}
+
+#[derive(Serialize)]
+struct FeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature>,
+}
+
+// an outline and a road feature per band, the outline (a convex hull, so a generous one) left
+// out while the roads span no area
+fn bands(station: Option<&str>, minutes: &[u32], isochrones: Vec<Isochrone>) -> Vec<Feature> {
+    minutes.iter().zip(isochrones).flat_map(|(&minutes, isochrone)| {
+        let band = |kind| Band { station: station.map(str::to_string), minutes, distance: isochrone.reach.round(), kind };
+        let area = Some(isochrone.outline.clone()).filter(|outline| !outline.is_empty()).map(|outline| Feature {
+            kind: "Feature",
+            properties: band("area"),
+            geometry: Geometry::Polygon { coordinates: vec![outline] },
+        });
+        let roads = Feature {
+            kind: "Feature",
+            properties: band("roads"),
+            geometry: Geometry::MultiLineString { coordinates: isochrone.roads },
+        };
+        area.into_iter().chain(Some(roads))
+    }).collect()
+}
+
+/// `/data/coverage`: GeoJSON of the areas and roads units reach within 5, 10 and 15 minutes
+/// (or the given `minutes`) of driving from each station, one station or, `combined`, any of them.
+pub fn isochrones(database: Data<Arc<Mutex<DatabaseAccess>>>, roads: Data<Arc<Mutex<Dispatcher>>>, config: Data<Config>, login: Json<CoverageInfo>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(_i) = info {
+        let mut minutes = login.minutes.clone().unwrap_or_else(|| COVERAGE_MINUTES.to_vec());
+        if minutes.is_empty() || minutes.iter().any(|m| *m == 0 || *m > MAX_COVERAGE_MINUTES) {
+            return result(&mut HttpResponse::BadRequest(), &format!("Minutes must be 1 to {} !", MAX_COVERAGE_MINUTES));
+        }
+        // widest first, so the narrower bands are drawn over them
+        minutes.sort_unstable_by(|m1, m2| m2.cmp(m1));
+        minutes.dedup();
+        let stations = database.lock().unwrap().find_police_station().into_iter()
+            .filter(|ps| login.station.as_ref().is_none_or(|id| *id == ps.id))
+            .map(|ps| (ps.id, Coordinates::from(ps.position)))
+            .collect::<Vec<_>>();
+        if stations.is_empty() && login.station.is_some() {
+            return result(&mut HttpResponse::NotFound(), "Station not found !");
+        }
+        // searched on a copy, the dispatcher keeps working meanwhile
+        let roads = roads.lock().unwrap().road_network();
+        if roads.is_empty() {
+            return result(&mut HttpResponse::ServiceUnavailable(), "Road network not loaded !");
+        }
+        // metres per minute
+        let reaches = minutes.iter().map(|m| f64::from(*m) * config.dispatch.speed * 1000f64 / 60f64).collect::<Vec<_>>();
+        let features = if login.combined {
+            let sources = stations.iter().map(|(_, at)| *at).collect::<Vec<_>>();
+            bands(None, &minutes, roads.isochrones(&sources, &reaches))
+        } else {
+            stations.iter()
+                .flat_map(|(id, at)| bands(Some(id.as_str()), &minutes, roads.isochrones(&[*at], &reaches)))
+                .collect()
+        };
+        return HttpResponse::Ok().json(FeatureCollection { kind: "FeatureCollection", features });
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
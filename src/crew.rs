@@ -0,0 +1,303 @@
+use actix::Addr;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Data, Json};
+use postgres::error::UNIQUE_VIOLATION;
+use log::error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::{Arc, Mutex};
+use crate::audit::{self, Auditor};
+use crate::database::{CrewMember, DatabaseAccess, Equipment};
+use crate::dispatcher::{DispatcherService, StationChanged};
+use crate::result;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DutyStatus {
+    #[default]
+    OnDuty,
+    OffDuty,
+    // busy outside of the dispatcher, e.g. sent by radio
+    Dispatched,
+}
+
+impl DutyStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DutyStatus::OnDuty => "on_duty",
+            DutyStatus::OffDuty => "off_duty",
+            DutyStatus::Dispatched => "dispatched",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [DutyStatus::OnDuty, DutyStatus::OffDuty, DutyStatus::Dispatched]
+            .iter().cloned().find(|s| s.as_str() == value)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EquipmentKind {
+    Vehicle,
+    Drone,
+}
+
+impl EquipmentKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EquipmentKind::Vehicle => "vehicle",
+            EquipmentKind::Drone => "drone",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [EquipmentKind::Vehicle, EquipmentKind::Drone].iter().cloned().find(|k| k.as_str() == value)
+    }
+}
+
+/// A time of day with minute precision, `HH:MM` in json.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ClockTime(i32);
+
+impl ClockTime {
+    pub fn from_minutes(minutes: i32) -> Self {
+        ClockTime(minutes.rem_euclid(24 * 60))
+    }
+
+    pub fn minutes(self) -> i32 {
+        self.0
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let (hours, minutes) = value.split_once(':')?;
+        let (hours, minutes) = (hours.parse::<i32>().ok()?, minutes.parse::<i32>().ok()?);
+        if (0..24).contains(&hours) && (0..60).contains(&minutes) && value.len() == 5 {
+            Some(ClockTime(hours * 60 + minutes))
+        } else {
+            None
+        }
+    }
+}
+
+impl Serialize for ClockTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for ClockTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        ClockTime::parse(&value).ok_or_else(|| serde::de::Error::custom(format!("'{}' is not a HH:MM time", value)))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteInfo {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ListInfo {
+    station: Option<String>,
+}
+
+fn validate(id: &str, capabilities: &[String], shift: (Option<ClockTime>, Option<ClockTime>)) -> Result<(), &'static str> {
+    if id.trim().is_empty() {
+        return Err("Id must not be empty !");
+    }
+    if capabilities.iter().any(|c| c.is_empty() || !c.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')) {
+        return Err("Capabilities must be lowercase letters, digits and underscores !");
+    }
+    if shift.0.is_some() != shift.1.is_some() {
+        return Err("A shift needs both its start and its end !");
+    }
+    Ok(())
+}
+
+fn station_exists(database: &DatabaseAccess, station: &str) -> bool {
+    database.find_police_station().iter().any(|ps| ps.id == station)
+}
+
+// the stations whose units changed, for the dispatcher to recount
+fn recount(dispatcher: &Addr<DispatcherService>, stations: &[&str]) {
+    let mut stations = stations.to_vec();
+    stations.dedup();
+    for station in stations {
+        dispatcher.do_send(StationChanged(station.to_string()));
+    }
+}
+
+pub fn list_crew(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<ListInfo>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(_i) = info {
+        return HttpResponse::Ok().json(database.lock().unwrap().find_crew(login.station.as_deref()));
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn add_crew(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<CrewMember>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Err(err) = validate(&login.id, &login.capabilities, (login.shift_start, login.shift_end)) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            if !station_exists(&db, &login.station) {
+                return result(&mut HttpResponse::NotFound(), "Station not found !");
+            }
+            return match db.add_crew(&login) {
+                Ok(_) => {
+                    Auditor::new(&i, ip).record(&db, "create", "crew", &login.id, None, Some(&*login));
+                    recount(&dispatcher, &[&login.station]);
+                    result(&mut HttpResponse::Ok(), "success")
+                }
+                Err(ref err) if err.code() == Some(&UNIQUE_VIOLATION) =>
+                    result(&mut HttpResponse::Conflict(), "Crew member already exists !"),
+                Err(err) => {
+                    error!("failed to add crew member {}: {}", login.id, err);
+                    result(&mut HttpResponse::InternalServerError(), "failed")
+                }
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+/// Replaces a crew member, going on or off duty or moving to another station included.
+pub fn update_crew(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<CrewMember>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Err(err) = validate(&login.id, &login.capabilities, (login.shift_start, login.shift_end)) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            let before = match db.find_crew(None).into_iter().find(|member| member.id == login.id) {
+                Some(member) => member,
+                None => return result(&mut HttpResponse::NotFound(), "Crew member not found !")
+            };
+            if !station_exists(&db, &login.station) {
+                return result(&mut HttpResponse::NotFound(), "Station not found !");
+            }
+            if db.update_crew(&before.station, &login) {
+                Auditor::new(&i, ip).record(&db, "update", "crew", &login.id, Some(&before), Some(&*login));
+                recount(&dispatcher, &[&before.station, &login.station]);
+            }
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn delete_crew(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<DeleteInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let db = database.lock().unwrap();
+            if let Some(before) = db.find_crew(None).into_iter().find(|member| member.id == login.id) {
+                if db.delete_crew(&before) {
+                    Auditor::new(&i, ip).record(&db, "delete", "crew", &login.id, Some(&before), None);
+                    recount(&dispatcher, &[&before.station]);
+                }
+            }
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn list_equipment(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<ListInfo>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(_i) = info {
+        return HttpResponse::Ok().json(database.lock().unwrap().find_equipment(login.station.as_deref()));
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn add_equipment(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<Equipment>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Err(err) = validate(&login.id, &login.capabilities, (login.shift_start, login.shift_end)) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            if !station_exists(&db, &login.station) {
+                return result(&mut HttpResponse::NotFound(), "Station not found !");
+            }
+            return match db.add_equipment(&login) {
+                Ok(_) => {
+                    Auditor::new(&i, ip).record(&db, "create", "equipment", &login.id, None, Some(&*login));
+                    recount(&dispatcher, &[&login.station]);
+                    result(&mut HttpResponse::Ok(), "success")
+                }
+                Err(ref err) if err.code() == Some(&UNIQUE_VIOLATION) =>
+                    result(&mut HttpResponse::Conflict(), "Equipment already exists !"),
+                Err(err) => {
+                    error!("failed to add equipment {}: {}", login.id, err);
+                    result(&mut HttpResponse::InternalServerError(), "failed")
+                }
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn update_equipment(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<Equipment>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Err(err) = validate(&login.id, &login.capabilities, (login.shift_start, login.shift_end)) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            let before = match db.find_equipment(None).into_iter().find(|equipment| equipment.id == login.id) {
+                Some(equipment) => equipment,
+                None => return result(&mut HttpResponse::NotFound(), "Equipment not found !")
+            };
+            if !station_exists(&db, &login.station) {
+                return result(&mut HttpResponse::NotFound(), "Station not found !");
+            }
+            if db.update_equipment(&before.station, &login) {
+                Auditor::new(&i, ip).record(&db, "update", "equipment", &login.id, Some(&before), Some(&*login));
+                recount(&dispatcher, &[&before.station, &login.station]);
+            }
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn delete_equipment(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<DeleteInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let db = database.lock().unwrap();
+            if let Some(before) = db.find_equipment(None).into_iter().find(|equipment| equipment.id == login.id) {
+                if db.delete_equipment(&before) {
+                    Auditor::new(&i, ip).record(&db, "delete", "equipment", &login.id, Some(&before), None);
+                    recount(&dispatcher, &[&before.station]);
+                }
+            }
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+#[test]
+fn test_clock_time() {
+    assert_eq!(ClockTime::parse("07:30"), Some(ClockTime(450)));
+    assert_eq!(ClockTime::parse("24:00"), None);
+    assert_eq!(ClockTime::parse("7:30"), None);
+    assert_eq!(serde_json::to_string(&ClockTime::from_minutes(-30)).unwrap(), "\"23:30\"");
+    assert!(serde_json::from_str::<ClockTime>("\"12:61\"").is_err());
+}
@@ -0,0 +1,111 @@
+use actix_web::dev::{Service, Transform, ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use actix_web::http::Method;
+use actix_web::error::InternalError;
+use futures::future::{ok, Either, FutureResult};
+use futures::Poll;
+
+pub const CSRF_COOKIE: &str = "csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// endpoints reachable before a csrf token has been issued
+const EXEMPT_PATHS: [&str; 1] = ["/user/login"];
+
+/// Double-submit csrf protection: every state-changing request must echo
+/// the `csrf` cookie issued at login back in the `X-CSRF-Token` header.
+pub struct CsrfGuard;
+
+impl<S, B> Transform<S> for CsrfGuard
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfGuardMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfGuardMiddleware { service })
+    }
+}
+
+pub struct CsrfGuardMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CsrfGuardMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if is_safe(req.method()) || EXEMPT_PATHS.contains(&req.path()) || verify_token(&req) {
+            Either::A(self.service.call(req))
+        } else {
+            let response = HttpResponse::Forbidden().content_type("application/json")
+                .body("{\"result\": \"CSRF token mismatch !\"}");
+            Either::B(ok(req.error_response(InternalError::from_response("csrf token mismatch", response))))
+        }
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    *method == Method::GET || *method == Method::HEAD || *method == Method::OPTIONS
+}
+
+fn verify_token(req: &ServiceRequest) -> bool {
+    let header = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+    match (req.cookie(CSRF_COOKIE), header) {
+        (Some(cookie), Some(header)) => !header.is_empty() && constant_time_eq(cookie.value().as_bytes(), header.as_bytes()),
+        _ => false
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_csrf_rejects_cross_site() {
+    use actix_web::{test, web, App};
+    use actix_web::http::{Cookie, StatusCode};
+
+    let mut app = test::init_service(
+        App::new()
+            .wrap(CsrfGuard)
+            .route("/user/login", web::post().to(HttpResponse::Ok))
+            .route("/mark/delete", web::post().to(HttpResponse::Ok))
+            .route("/data/road.geojson", web::get().to(HttpResponse::Ok))
+    );
+    // a forged cross-site form post carries the cookies but cannot read them
+    let forged = test::TestRequest::post().uri("/mark/delete")
+        .cookie(Cookie::new(CSRF_COOKIE, "secret")).to_request();
+    assert_eq!(test::call_service(&mut app, forged).status(), StatusCode::FORBIDDEN);
+    let guessed = test::TestRequest::post().uri("/mark/delete")
+        .cookie(Cookie::new(CSRF_COOKIE, "secret"))
+        .header(CSRF_HEADER, "guess").to_request();
+    assert_eq!(test::call_service(&mut app, guessed).status(), StatusCode::FORBIDDEN);
+    let no_cookie = test::TestRequest::post().uri("/mark/delete")
+        .header(CSRF_HEADER, "secret").to_request();
+    assert_eq!(test::call_service(&mut app, no_cookie).status(), StatusCode::FORBIDDEN);
+    let genuine = test::TestRequest::post().uri("/mark/delete")
+        .cookie(Cookie::new(CSRF_COOKIE, "secret"))
+        .header(CSRF_HEADER, "secret").to_request();
+    assert_eq!(test::call_service(&mut app, genuine).status(), StatusCode::OK);
+    let login = test::TestRequest::post().uri("/user/login").to_request();
+    assert_eq!(test::call_service(&mut app, login).status(), StatusCode::OK);
+    let read = test::TestRequest::get().uri("/data/road.geojson").to_request();
+    assert_eq!(test::call_service(&mut app, read).status(), StatusCode::OK);
+}
@@ -1,10 +1,129 @@
 use postgres::*;
 use serde::{Deserialize, Serialize};
-use rust_decimal::prelude::*;
-use rust_decimal::*;
+use log::warn;
+use crate::metrics::{DB_QUERY_DURATION, statement_label};
+use crate::dispatch::{Coordinates, Dispatch, Workload};
+use crate::incident::{IncidentStatus, OPEN_STATUSES};
+use crate::audit::AuditFilter;
+use crate::crew::{ClockTime, DutyStatus, EquipmentKind};
+use uuid::Uuid;
+
+// incident rows as read by `mark_from_row`
+const MARK_COLUMNS: &str = "id::text, positionX, positionY, positionZ, drone, height, level, description, status, incident_type, units";
+
+// sessions idle for longer than this are considered expired (in seconds)
+pub const SESSION_TTL: i64 = 2 * 60 * 60;
+
+// deletions stay in the change log this long for clients to sync them (in seconds)
+const CHANGE_LOG_RETENTION: i64 = 7 * 24 * 60 * 60;
 
 pub struct DatabaseAccess {
-    conn: Connection
+    conn: TimedConnection,
+    session_ttl: i64,
+}
+
+// records the latency of every statement for the /metrics endpoint
+struct TimedConnection(Connection);
+
+impl TimedConnection {
+    fn execute(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<u64> {
+        let _timer = DB_QUERY_DURATION.with_label_values(&[&statement_label(sql)]).start_timer();
+        self.0.execute(sql, params)
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<rows::Rows> {
+        let _timer = DB_QUERY_DURATION.with_label_values(&[&statement_label(sql)]).start_timer();
+        self.0.query(sql, params)
+    }
+
+    fn transaction(&self) -> Result<transaction::Transaction<'_>> {
+        self.0.transaction()
+    }
+}
+
+// statements shared by plain calls and the ones that have to commit together
+trait Statements {
+    fn run(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<u64>;
+    fn rows(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<rows::Rows>;
+}
+
+impl Statements for TimedConnection {
+    fn run(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<u64> {
+        self.execute(sql, params)
+    }
+
+    fn rows(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<rows::Rows> {
+        self.query(sql, params)
+    }
+}
+
+impl Statements for transaction::Transaction<'_> {
+    fn run(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<u64> {
+        let _timer = DB_QUERY_DURATION.with_label_values(&[&statement_label(sql)]).start_timer();
+        self.execute(sql, params)
+    }
+
+    fn rows(&self, sql: &str, params: &[&dyn types::ToSql]) -> Result<rows::Rows> {
+        let _timer = DB_QUERY_DURATION.with_label_values(&[&statement_label(sql)]).start_timer();
+        self.query(sql, params)
+    }
+}
+
+fn log_change(conn: &dyn Statements, entity: &str, id: &str, data: Option<String>) -> Result<u64> {
+    conn.run("INSERT INTO change_log (entity, entity_id, data, changed_at) VALUES ($1, $2, $3, $4)",
+             &[&entity, &id, &data, &crate::unix_timestamp()])
+}
+
+fn insert_route(conn: &dyn Statements, belong: Uuid, route: &[(f64, f64)]) -> Result<u64> {
+    let (xs, ys): (Vec<_>, Vec<_>) = route.iter().cloned().unzip();
+    conn.run("INSERT INTO dispatch_routes (belong, xs, ys) VALUES ($1::text::uuid, $2, $3)",
+             &[&belong.to_string(), &xs, &ys])
+}
+
+fn find_routes(conn: &dyn Statements, belong: Uuid) -> Result<Vec<Vec<(f64, f64)>>> {
+    let rows = conn.rows("SELECT xs, ys FROM dispatch_routes WHERE belong=$1::text::uuid ORDER BY id", &[&belong.to_string()])?;
+    Ok(rows.iter().map(|row| {
+        row.get::<usize, Vec<f64>>(0).into_iter().zip(row.get::<usize, Vec<f64>>(1)).collect()
+    }).collect())
+}
+
+fn log_routes(conn: &dyn Statements, belong: Uuid) -> Result<u64> {
+    let routes = IncidentRoutes { incident: belong, routes: find_routes(conn, belong)? };
+    log_change(conn, "route", &belong.to_string(), Some(serde_json::to_string(&routes).unwrap()))
+}
+
+fn replace_routes(conn: &dyn Statements, incident: Uuid, routes: &[Vec<(f64, f64)>]) -> Result<()> {
+    let removed = conn.run("DELETE FROM dispatch_routes WHERE belong=$1::text::uuid", &[&incident.to_string()])?;
+    for route in routes {
+        insert_route(conn, incident, route)?;
+    }
+    if !routes.is_empty() {
+        log_routes(conn, incident)?;
+    } else if removed > 0 {
+        log_change(conn, "route", &incident.to_string(), None)?;
+    }
+    Ok(())
+}
+
+fn record_transition(conn: &dyn Statements, id: Uuid, from: Option<IncidentStatus>, to: IncidentStatus, reason: Option<&str>, actor: &str) -> Result<u64> {
+    conn.run("INSERT INTO incident_transitions (incident, from_status, to_status, reason, actor, changed_at) VALUES ($1::text::uuid, $2, $3, $4, $5, $6)"
+             , &[&id.to_string(), &from.map(|s| s.as_str()), &to.as_str(), &reason, &actor, &crate::unix_timestamp()])
+}
+
+// false when the incident is no longer in `from`
+fn transition_mark(conn: &dyn Statements, id: Uuid, from: IncidentStatus, to: IncidentStatus, reason: Option<&str>, actor: &str) -> Result<bool> {
+    let updated = conn.run(
+        "UPDATE telephone_operator_data SET status=$3, closure_reason=COALESCE($4, closure_reason) WHERE id=$1::text::uuid AND status=$2"
+        , &[&id.to_string(), &from.as_str(), &to.as_str(), &reason])?;
+    if updated == 0 {
+        return Ok(false);
+    }
+    record_transition(conn, id, Some(from), to, reason, actor)?;
+    let rows = conn.rows(&format!("SELECT {} FROM telephone_operator_data WHERE id=$1::text::uuid", MARK_COLUMNS), &[&id.to_string()])?;
+    if let Some(row) = rows.iter().next() {
+        log_change(conn, "mark", &id.to_string(), Some(serde_json::to_string(&DatabaseAccess::mark_from_row(&row)).unwrap()))?;
+    }
+    Ok(true)
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -13,6 +132,19 @@ pub struct User {
     pub passwd: String,
     #[serde(rename = "type")]
     pub user_type: i32,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default)]
+    pub must_change: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UserSummary {
+    pub username: String,
+    #[serde(rename = "type")]
+    pub user_type: i32,
+    pub disabled: bool,
+    pub must_change: bool,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -23,6 +155,26 @@ pub struct LoginInfo {
     pub user_type: i32,
 }
 
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Session {
+    pub id: i32,
+    pub username: String,
+    #[serde(rename = "type")]
+    pub user_type: i32,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Lockout {
+    pub key: String,
+    pub failures: i32,
+    pub ip: String,
+    pub created_at: i64,
+    pub locked_until: i64,
+}
+
 #[derive(Deserialize, Serialize, Clone, Copy)]
 pub struct Position {
     pub x: f64,
@@ -39,20 +191,115 @@ pub struct PoliceStation {
     pub drones: i32,
 }
 
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CrewMember {
+    pub id: String,
+    pub station: String,
+    pub name: String,
+    #[serde(default)]
+    pub status: DutyStatus,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub shift_start: Option<ClockTime>,
+    #[serde(default)]
+    pub shift_end: Option<ClockTime>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Equipment {
+    pub id: String,
+    pub station: String,
+    pub kind: EquipmentKind,
+    #[serde(default)]
+    pub status: DutyStatus,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub shift_start: Option<ClockTime>,
+    #[serde(default)]
+    pub shift_end: Option<ClockTime>,
+}
+
+/// A weekly shift of a station; `weekday` 1 is monday, a shift ending at or before its
+/// start runs into the next day.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Shift {
+    #[serde(default)]
+    pub station: String,
+    pub weekday: i32,
+    pub start: ClockTime,
+    pub end: ClockTime,
+    pub crew: i32,
+    pub drones: i32,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct OperatorMark {
-    pub uid: u128,
+    pub uid: Uuid,
     pub position: Position,
     pub height: f64,
     pub level: i32,
     pub drone: bool,
     pub desc: String,
+    #[serde(default)]
+    pub status: IncidentStatus,
+    // code in the incident type catalogue, none for incidents reported by level only
+    #[serde(rename = "type", default)]
+    pub kind: Option<String>,
+    // units required
+    pub units: i32,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct IncidentType {
+    pub code: String,
+    pub name: String,
+    // dispatch severity, the level of incidents of this type
+    pub priority: i32,
+    pub units: i32,
+    pub unit_types: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Transition {
+    pub from: Option<IncidentStatus>,
+    pub to: IncidentStatus,
+    pub reason: Option<String>,
+    pub actor: String,
+    pub changed_at: i64,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct DispatchedRoutes {
     pub route: Vec<(f64, f64)>,
-    pub belong: usize,
+    pub belong: Uuid,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct IncidentRoutes {
+    pub incident: Uuid,
+    pub routes: Vec<Vec<(f64, f64)>>,
+}
+
+pub struct Change {
+    pub seq: i64,
+    pub entity: String,
+    pub entity_id: String,
+    pub data: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: String,
+    pub action: String,
+    pub entity: String,
+    pub entity_id: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub ip: String,
+    pub logged_at: i64,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -63,30 +310,74 @@ pub struct UnifiedData {
     _3ddstoken: String,
 }
 
+// uuids travel as text, the postgres driver here only knows an older uuid crate
+fn uuid_at(row: &rows::Row, index: usize) -> Uuid {
+    Uuid::parse_str(&row.get::<usize, String>(index)).unwrap()
+}
+
 impl DatabaseAccess {
     pub fn new(url: &'_ str) -> Result<Self> {
         Connection::connect(url, TlsMode::None).map(|conn|
             Self {
-                conn
+                conn: TimedConnection(conn),
+                session_ttl: SESSION_TTL,
             }
         )
     }
+
+    pub fn with_session_ttl(mut self, ttl: i64) -> Self {
+        self.session_ttl = ttl;
+        self
+    }
 }
 
 impl DatabaseAccess {
     pub fn init(&self) {
+        // gen_random_uuid is built in from PostgreSQL 13, older servers take it from pgcrypto
+        let builtin_uuid = self.conn.query("SELECT to_regproc('gen_random_uuid') IS NOT NULL", &[]).unwrap().get(0).get::<usize, bool>(0);
+        if !builtin_uuid {
+            self.conn.execute("CREATE EXTENSION IF NOT EXISTS pgcrypto", &[])
+                .expect("incident ids need PostgreSQL 13 or the pgcrypto extension");
+        }
         self.conn.execute("CREATE TABLE IF NOT EXISTS user_data (
                     id              SERIAL PRIMARY KEY,
                     name            VARCHAR NOT NULL,
                     passwd          VARCHAR NOT NULL,
                     type            INT
                   )", &[]).unwrap();
+        self.conn.execute("ALTER TABLE user_data
+                    ADD COLUMN IF NOT EXISTS disabled BOOL NOT NULL DEFAULT FALSE,
+                    ADD COLUMN IF NOT EXISTS must_change BOOL NOT NULL DEFAULT FALSE
+                  ", &[]).unwrap();
+        if let Err(err) = self.conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS user_data_name_key ON user_data (name)", &[]) {
+            warn!("duplicate user names prevent enforcing unique user_data.name: {}", err);
+        }
         self.conn.execute("CREATE TABLE IF NOT EXISTS login_data (
                     id              SERIAL PRIMARY KEY,
                     token           VARCHAR NOT NULL,
                     name            VARCHAR NOT NULL,
                     type            INT
                   )", &[]).unwrap();
+        // sessions created before expiry tracking default to already expired
+        self.conn.execute("ALTER TABLE login_data
+                    ADD COLUMN IF NOT EXISTS created_at BIGINT NOT NULL DEFAULT 0,
+                    ADD COLUMN IF NOT EXISTS last_seen BIGINT NOT NULL DEFAULT 0,
+                    ADD COLUMN IF NOT EXISTS expires_at BIGINT NOT NULL DEFAULT 0
+                  ", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS login_attempts (
+                    key             VARCHAR PRIMARY KEY,
+                    failures        INT NOT NULL,
+                    last_failure    BIGINT NOT NULL,
+                    locked_until    BIGINT NOT NULL DEFAULT 0
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS login_lockouts (
+                    id              SERIAL PRIMARY KEY,
+                    key             VARCHAR NOT NULL,
+                    failures        INT NOT NULL,
+                    ip              VARCHAR NOT NULL,
+                    created_at      BIGINT NOT NULL,
+                    locked_until    BIGINT NOT NULL
+                  )", &[]).unwrap();
         self.conn.execute("CREATE TABLE IF NOT EXISTS police_station_data (
                     id              SERIAL PRIMARY KEY,
                     uid             VARCHAR NOT NULL,
@@ -97,10 +388,45 @@ impl DatabaseAccess {
                     crew            VARCHAR[],
                     drone           INT
                   )", &[]).unwrap();
-        self.conn.execute("DROP TABLE IF EXISTS telephone_operator_data", &[]).unwrap();
-        self.conn.execute("CREATE TABLE telephone_operator_data (
+        // station ids used to be free to repeat, only the first station with an id is kept
+        let duplicates = self.conn.execute("DELETE FROM police_station_data a USING police_station_data b WHERE a.uid = b.uid AND a.id > b.id", &[]).unwrap();
+        if duplicates > 0 {
+            warn!("removed {} station(s) repeating the id of an earlier one", duplicates);
+        }
+        self.conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS police_station_uid ON police_station_data (uid)", &[]).unwrap();
+        let seed_crew = self.conn.query("SELECT to_regclass('crew_members') IS NULL", &[]).unwrap().get(0).get::<usize, bool>(0);
+        self.conn.execute("CREATE TABLE IF NOT EXISTS crew_members (
+                    id              VARCHAR PRIMARY KEY,
+                    station         VARCHAR NOT NULL,
+                    name            VARCHAR NOT NULL,
+                    status          VARCHAR NOT NULL DEFAULT 'on_duty',
+                    capabilities    VARCHAR[] NOT NULL DEFAULT '{}',
+                    shift_start     INT,
+                    shift_end       INT
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS station_equipment (
+                    id              VARCHAR PRIMARY KEY,
+                    station         VARCHAR NOT NULL,
+                    kind            VARCHAR NOT NULL,
+                    status          VARCHAR NOT NULL DEFAULT 'on_duty',
+                    capabilities    VARCHAR[] NOT NULL DEFAULT '{}',
+                    shift_start     INT,
+                    shift_end       INT
+                  )", &[]).unwrap();
+        if seed_crew {
+            self.migrate_station_crew();
+        }
+        self.conn.execute("CREATE TABLE IF NOT EXISTS station_shifts (
                     id              SERIAL PRIMARY KEY,
-                    uid             DECIMAL,
+                    station         VARCHAR NOT NULL,
+                    weekday         INT NOT NULL,
+                    shift_start     INT NOT NULL,
+                    shift_end       INT NOT NULL,
+                    crew            INT NOT NULL,
+                    drones          INT NOT NULL
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS telephone_operator_data (
+                    id              UUID PRIMARY KEY DEFAULT gen_random_uuid(),
                     positionX       DOUBLE PRECISION,
                     positionY       DOUBLE PRECISION,
                     positionZ       DOUBLE PRECISION,
@@ -109,23 +435,127 @@ impl DatabaseAccess {
                     level           INT,
                     description     VARCHAR
                   )", &[]).unwrap();
-        self.conn.execute("DROP TABLE IF EXISTS dispatch_routes", &[]).unwrap();
-        self.conn.execute("CREATE TABLE dispatch_routes (
+        self.conn.execute("ALTER TABLE telephone_operator_data
+                    ADD COLUMN IF NOT EXISTS status VARCHAR NOT NULL DEFAULT 'reported',
+                    ADD COLUMN IF NOT EXISTS reported_at BIGINT,
+                    ADD COLUMN IF NOT EXISTS closure_reason VARCHAR,
+                    ADD COLUMN IF NOT EXISTS incident_type VARCHAR,
+                    ADD COLUMN IF NOT EXISTS units INT", &[]).unwrap();
+        // incidents from before the catalogue need one unit more than their level
+        self.conn.execute("UPDATE telephone_operator_data SET units = level + 1 WHERE units IS NULL", &[]).unwrap();
+        let seed_types = self.conn.query("SELECT to_regclass('incident_types') IS NULL", &[]).unwrap().get(0).get::<usize, bool>(0);
+        self.conn.execute("CREATE TABLE IF NOT EXISTS incident_types (
+                    code            VARCHAR PRIMARY KEY,
+                    name            VARCHAR NOT NULL,
+                    priority        INT NOT NULL,
+                    units           INT NOT NULL,
+                    unit_types      VARCHAR[] NOT NULL
+                  )", &[]).unwrap();
+        if seed_types {
+            for kind in crate::incident_type::defaults() {
+                self.add_incident_type(&kind).unwrap();
+            }
+        }
+        self.conn.execute("CREATE TABLE IF NOT EXISTS incident_transitions (
+                    id              SERIAL PRIMARY KEY,
+                    incident        UUID,
+                    from_status     VARCHAR,
+                    to_status       VARCHAR,
+                    reason          VARCHAR,
+                    actor           VARCHAR,
+                    changed_at      BIGINT
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS dispatch_routes (
                     id              SERIAL PRIMARY KEY,
-                    belong          INT,
+                    belong          UUID,
                     xs              DOUBLE PRECISION[],
                     ys              DOUBLE PRECISION[]
                     )", &[]).unwrap();
+        // every change to marks, stations and routes, replayed by /sync; `data` is NULL for deletions
+        self.conn.execute("CREATE TABLE IF NOT EXISTS change_log (
+                    seq             BIGSERIAL PRIMARY KEY,
+                    entity          VARCHAR,
+                    entity_id       VARCHAR,
+                    data            VARCHAR,
+                    changed_at      BIGINT
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE INDEX IF NOT EXISTS change_log_entity ON change_log (entity, entity_id, seq)", &[]).unwrap();
+        // the newest deletion trimmed from the change log, older cursors cannot be resumed
+        self.conn.execute("CREATE TABLE IF NOT EXISTS change_log_horizon (
+                    seq             BIGINT NOT NULL
+                  )", &[]).unwrap();
+        // dispatcher state saved on shutdown, restored (and emptied) on the next start
+        self.conn.execute("CREATE TABLE IF NOT EXISTS dispatcher_snapshots (
+                    taken_at        BIGINT
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS pending_workloads (
+                    id              UUID,
+                    assign_id       UUID,
+                    severity        BIGINT,
+                    consumption     BIGINT,
+                    positionX       DOUBLE PRECISION,
+                    positionY       DOUBLE PRECISION,
+                    positionZ       DOUBLE PRECISION,
+                    drone           BOOL
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE TABLE IF NOT EXISTS active_dispatches (
+                    id              BIGINT,
+                    power           BIGINT,
+                    severity        BIGINT,
+                    positionX       DOUBLE PRECISION,
+                    positionY       DOUBLE PRECISION,
+                    positionZ       DOUBLE PRECISION,
+                    assign_id       UUID,
+                    source          VARCHAR,
+                    to_id           UUID
+                  )", &[]).unwrap();
+        self.conn.execute("ALTER TABLE active_dispatches ADD COLUMN IF NOT EXISTS drones BIGINT NOT NULL DEFAULT 0", &[]).unwrap();
+        // incidents waiting for the dispatcher, written with the incident and completed with its dispatch
+        self.conn.execute("CREATE TABLE IF NOT EXISTS dispatch_outbox (
+                    id              BIGSERIAL PRIMARY KEY,
+                    incident        UUID NOT NULL,
+                    created_at      BIGINT NOT NULL,
+                    processed_at    BIGINT
+                  )", &[]).unwrap();
+        // stations dispatchers excluded from an incident, dropped once it is closed
+        self.conn.execute("CREATE TABLE IF NOT EXISTS dispatch_exclusions (
+                    incident        UUID NOT NULL,
+                    station         VARCHAR NOT NULL,
+                    PRIMARY KEY (incident, station)
+                  )", &[]).unwrap();
         self.conn.execute("CREATE TABLE IF NOT EXISTS init_data (
                     key             VARCHAR PRIMARY KEY,
                     value           VARCHAR
                   )", &[]).unwrap();
+        // who changed what; rows are only ever inserted, the trigger refuses anything else
+        self.conn.execute("CREATE TABLE IF NOT EXISTS audit_log (
+                    id              BIGSERIAL PRIMARY KEY,
+                    actor           VARCHAR NOT NULL,
+                    action          VARCHAR NOT NULL,
+                    entity          VARCHAR NOT NULL,
+                    entity_id       VARCHAR,
+                    before_data     VARCHAR,
+                    after_data      VARCHAR,
+                    ip              VARCHAR,
+                    logged_at       BIGINT NOT NULL
+                  )", &[]).unwrap();
+        self.conn.execute("CREATE INDEX IF NOT EXISTS audit_log_entity ON audit_log (entity, entity_id)", &[]).unwrap();
+        self.conn.execute("CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+                    BEGIN
+                        RAISE EXCEPTION 'audit_log is append-only';
+                    END
+                  $$ LANGUAGE plpgsql", &[]).unwrap();
+        self.conn.execute("DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log", &[]).unwrap();
+        self.conn.execute("CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_log
+                    FOR EACH STATEMENT EXECUTE PROCEDURE audit_log_append_only()", &[]).unwrap();
+        self.migrate_incident_ids();
+        self.backfill_change_log();
     }
 
-    pub fn add_user(&self, user: User) {
+    pub fn add_user(&self, user: User) -> Result<u64> {
         self.conn.execute(
-            "INSERT INTO user_data (name, passwd, type) VALUES ($1, $2, $3) "
-            , &[&user.username, &user.passwd, &user.user_type]).unwrap();
+            "INSERT INTO user_data (name, passwd, type, disabled, must_change) VALUES ($1, $2, $3, $4, $5) "
+            , &[&user.username, &user.passwd, &user.user_type, &user.disabled, &user.must_change])
     }
 
     pub fn find_user(&self, username: String) -> Option<User> {
@@ -137,60 +567,437 @@ impl DatabaseAccess {
                 username: row.get(1),
                 passwd: row.get(2),
                 user_type: row.get(3),
+                disabled: row.get(4),
+                must_change: row.get(5),
             }
         }).collect();
-        return users.first().map(|u| u.clone());
+        users.first().cloned()
+    }
+
+    pub fn find_users(&self) -> Vec<UserSummary> {
+        let rows = self.conn
+            .query("SELECT name, type, disabled, must_change FROM user_data ORDER BY id",
+                   &[]).unwrap();
+        rows.iter().map(|row| {
+            UserSummary {
+                username: row.get(0),
+                user_type: row.get(1),
+                disabled: row.get(2),
+                must_change: row.get(3),
+            }
+        }).collect()
+    }
+
+    // enabled administrators other than the given user
+    pub fn count_other_admins(&self, username: &str) -> i64 {
+        let rows = self.conn
+            .query("SELECT COUNT(*) FROM user_data WHERE type=1 AND NOT disabled AND name<>$1",
+                   &[&username]).unwrap();
+        rows.get(0).get(0)
+    }
+
+    pub fn update_user_type(&self, username: &str, user_type: i32) -> bool {
+        let updated = self.conn.execute("UPDATE user_data SET type=$2 WHERE name=$1"
+                                        , &[&username, &user_type]).unwrap_or(0) > 0;
+        if updated {
+            self.revoke_sessions(username);
+        }
+        updated
+    }
+
+    pub fn set_user_disabled(&self, username: &str, disabled: bool) -> bool {
+        let updated = self.conn.execute("UPDATE user_data SET disabled=$2 WHERE name=$1"
+                                        , &[&username, &disabled]).unwrap_or(0) > 0;
+        if updated && disabled {
+            self.revoke_sessions(username);
+        }
+        updated
+    }
+
+    // must_change forces the user to rotate the password at next login
+    pub fn change_password(&self, username: &str, passwd: &str, must_change: bool) -> bool {
+        let updated = self.conn.execute("UPDATE user_data SET passwd=$2, must_change=$3 WHERE name=$1"
+                                        , &[&username, &passwd, &must_change]).unwrap_or(0) > 0;
+        if updated {
+            self.revoke_sessions(username);
+        }
+        updated
+    }
+
+    pub fn password_change_required(&self, token: &str) -> bool {
+        let rows = self.conn
+            .query("SELECT user_data.must_change FROM login_data JOIN user_data ON login_data.name = user_data.name WHERE login_data.token=$1",
+                   &[&token]).unwrap();
+        rows.iter().any(|row| row.get::<usize, bool>(0))
     }
 
     pub fn delete_user(&self, username: String) -> bool {
-        self.conn.execute("DELETE FROM user_data WHERE name=$1"
-                          , &[&username]).is_ok()
+        let deleted = self.conn.execute("DELETE FROM user_data WHERE name=$1"
+                                        , &[&username]).is_ok();
+        if deleted {
+            self.revoke_sessions(&username);
+        }
+        deleted
     }
 }
 
 impl DatabaseAccess {
-    pub fn add_route(&self, route: DispatchedRoutes) {
-        let iters: (Vec<_>, Vec<_>) = route.route.iter().cloned().unzip();
-        self.conn.execute("INSERT INTO dispatch_routes (belong, xs, ys) VALUES ($1, $2, $3)",
-                          &[&(route.belong as i32), &iters.0, &&iters.1]).unwrap();
+    /// Stores the routes of new missions, moves a reported incident to dispatched
+    /// and completes its outbox entry, all or nothing. True when the status changed.
+    pub fn record_dispatch(&self, incident: Uuid, routes: &[Vec<(f64, f64)>], outbox: Option<i64>) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        for route in routes {
+            insert_route(&tx, incident, route)?;
+        }
+        let dispatched = if routes.is_empty() {
+            false
+        } else {
+            log_routes(&tx, incident)?;
+            transition_mark(&tx, incident, IncidentStatus::Reported, IncidentStatus::Dispatched, None, "dispatcher")?
+        };
+        if let Some(entry) = outbox {
+            tx.run("UPDATE dispatch_outbox SET processed_at=$2 WHERE id=$1", &[&entry, &crate::unix_timestamp()])?;
+        }
+        tx.commit()?;
+        Ok(dispatched)
+    }
+
+    /// Swaps every route of an incident for `routes` in one transaction.
+    pub fn replace_routes(&self, incident: Uuid, routes: &[Vec<(f64, f64)>]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        replace_routes(&tx, incident, routes)?;
+        tx.commit()
+    }
+
+    /// Stores a dispatcher's manual change to an incident: its routes, its excluded stations
+    /// when given, and the move from reported to dispatched once units are on their way.
+    /// True when the status changed.
+    pub fn override_dispatch(&self, incident: Uuid, routes: &[Vec<(f64, f64)>], excluded: Option<&[String]>, actor: &str) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        replace_routes(&tx, incident, routes)?;
+        if let Some(excluded) = excluded {
+            tx.run("DELETE FROM dispatch_exclusions WHERE incident=$1::text::uuid", &[&incident.to_string()])?;
+            for station in excluded {
+                tx.run("INSERT INTO dispatch_exclusions (incident, station) VALUES ($1::text::uuid, $2)", &[&incident.to_string(), station])?;
+            }
+        }
+        let dispatched = !routes.is_empty()
+            && transition_mark(&tx, incident, IncidentStatus::Reported, IncidentStatus::Dispatched, None, actor)?;
+        tx.commit()?;
+        Ok(dispatched)
+    }
+
+    /// Stations excluded from each incident, by incident.
+    pub fn find_exclusions(&self) -> Vec<(Uuid, String)> {
+        self.conn.query("SELECT incident::text, station FROM dispatch_exclusions ORDER BY incident, station", &[]).unwrap()
+            .iter().map(|row| (uuid_at(&row, 0), row.get(1))).collect()
+    }
+
+    pub fn clear_exclusions(&self, incident: Uuid) -> Result<u64> {
+        self.conn.execute("DELETE FROM dispatch_exclusions WHERE incident=$1::text::uuid", &[&incident.to_string()])
     }
 
     pub fn get_routes(&self) -> Vec<DispatchedRoutes> {
         let rows = self.conn
-            .query("SELECT * FROM dispatch_routes", &[]).unwrap();
+            .query("SELECT id, belong::text, xs, ys FROM dispatch_routes", &[]).unwrap();
         rows.iter().map(|row| {
             DispatchedRoutes {
-                belong: row.get::<usize, i32>(1) as usize,
+                belong: uuid_at(&row, 1),
                 route: row.get::<usize, Vec<f64>>(2).into_iter().zip(row.get::<usize, Vec<f64>>(3)).collect::<Vec<_>>(),
             }
         }).collect()
     }
 
-    pub fn remove_routes(&self, belong: usize) -> Result<u64> {
-        self.conn.execute("DELETE FROM dispatch_routes WHERE belong=$1", &[&(belong as i32)])
+    pub fn find_routes(&self, belong: Uuid) -> Vec<Vec<(f64, f64)>> {
+        find_routes(&self.conn, belong).unwrap()
+    }
+
+    pub fn remove_routes(&self, belong: Uuid) -> Result<u64> {
+        let removed = self.conn.execute("DELETE FROM dispatch_routes WHERE belong=$1::text::uuid", &[&belong.to_string()])?;
+        if removed > 0 {
+            self.log_change("route", &belong.to_string(), None);
+        }
+        Ok(removed)
     }
 }
 
 impl DatabaseAccess {
+    /// Replaces the saved dispatcher state with the given pending workloads and dispatches.
+    pub fn save_dispatcher_state(&self, pending: &[Workload], dispatches: &[Dispatch]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM pending_workloads", &[])?;
+        tx.execute("DELETE FROM active_dispatches", &[])?;
+        tx.execute("DELETE FROM dispatcher_snapshots", &[])?;
+        for w in pending {
+            tx.execute("INSERT INTO pending_workloads (id, assign_id, severity, consumption, positionX, positionY, positionZ, drone) VALUES ($1::text::uuid, $2::text::uuid, $3, $4, $5, $6, $7, $8)",
+                       &[&w.id.to_string(), &w.assign_id.to_string(), &(w.severity as i64), &(w.consumption as i64),
+                           &w.location.x, &w.location.y, &w.location.h, &w.drone])?;
+        }
+        for d in dispatches {
+            tx.execute("INSERT INTO active_dispatches (id, power, severity, positionX, positionY, positionZ, assign_id, source, to_id, drones) VALUES ($1, $2, $3, $4, $5, $6, $7::text::uuid, $8, $9::text::uuid, $10)",
+                       &[&(d.id as i64), &(d.power as i64), &(d.severity as i64),
+                           &d.location.x, &d.location.y, &d.location.h, &d.assign.to_string(), &d.source, &d.to_id.to_string(), &(d.drones as i64)])?;
+        }
+        tx.execute("INSERT INTO dispatcher_snapshots (taken_at) VALUES ($1)", &[&crate::unix_timestamp()])?;
+        tx.commit()
+    }
+
+    /// Reads and clears the dispatcher state saved on the last shutdown,
+    /// `None` when the previous run did not stop cleanly.
+    pub fn take_dispatcher_state(&self) -> Option<(Vec<Workload>, Vec<Dispatch>)> {
+        let tx = self.conn.transaction().ok()?;
+        let saved = tx.execute("DELETE FROM dispatcher_snapshots", &[]).ok()? > 0;
+        let pending = tx.query("DELETE FROM pending_workloads RETURNING id::text, assign_id::text, severity, consumption, positionX, positionY, positionZ, drone", &[]).ok()?
+            .iter().map(|row| Workload {
+                is_remove: false,
+                id: uuid_at(&row, 0),
+                assign_id: uuid_at(&row, 1),
+                severity: row.get::<usize, i64>(2) as usize,
+                consumption: row.get::<usize, i64>(3) as usize,
+                location: Coordinates { x: row.get(4), y: row.get(5), h: row.get(6) },
+                drone: row.get(7),
+                excluded: vec![],
+            }).collect();
+        let dispatches = tx.query("DELETE FROM active_dispatches RETURNING id, power, severity, positionX, positionY, positionZ, assign_id::text, source, to_id::text, drones", &[]).ok()?
+            .iter().map(|row| Dispatch {
+                id: row.get::<usize, i64>(0) as usize,
+                power: row.get::<usize, i64>(1) as usize,
+                drones: row.get::<usize, i64>(9) as usize,
+                severity: row.get::<usize, i64>(2) as usize,
+                location: Coordinates { x: row.get(3), y: row.get(4), h: row.get(5) },
+                assign: uuid_at(&row, 6),
+                source: row.get(7),
+                to_id: uuid_at(&row, 8),
+            }).collect();
+        tx.commit().ok()?;
+        if saved {
+            Some((pending, dispatches))
+        } else {
+            None
+        }
+    }
+
+    pub fn clear_routes(&self) -> Result<u64> {
+        let incidents = self.conn.query("SELECT DISTINCT belong::text FROM dispatch_routes", &[])?;
+        let removed = self.conn.execute("DELETE FROM dispatch_routes", &[])?;
+        for row in incidents.iter() {
+            self.log_change("route", &row.get::<usize, String>(0), None);
+        }
+        Ok(removed)
+    }
+
+    fn log_routes(&self, belong: Uuid) {
+        if let Err(err) = log_routes(&self.conn, belong) {
+            warn!("unable to record the change of route {}: {}", belong, err);
+        }
+    }
+
+    fn log_change(&self, entity: &str, id: &str, data: Option<String>) {
+        if let Err(err) = log_change(&self.conn, entity, id, data) {
+            warn!("unable to record the change of {} {}: {}", entity, id, err);
+        }
+    }
+
+    // stations used to keep their crew as a list of names and their drones as a count,
+    // every one of them becomes an on duty member or drone of its own
+    fn migrate_station_crew(&self) {
+        let stations = self.conn.query("SELECT uid, crew, drone FROM police_station_data", &[]).unwrap();
+        let tx = self.conn.transaction().unwrap();
+        for row in stations.iter() {
+            let station: String = row.get(0);
+            let crew = row.get::<usize, Option<Vec<String>>>(1).unwrap_or_default();
+            for (index, name) in crew.iter().enumerate() {
+                tx.execute("INSERT INTO crew_members (id, station, name) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                           &[&format!("{}-{}", station, index + 1), &station, name]).unwrap();
+            }
+            for index in 0..row.get::<usize, Option<i32>>(2).unwrap_or(0).max(0) {
+                tx.execute("INSERT INTO station_equipment (id, station, kind) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                           &[&format!("{}-drone-{}", station, index + 1), &station, &EquipmentKind::Drone.as_str()]).unwrap();
+            }
+        }
+        tx.commit().unwrap();
+    }
+
+    // seeds the change log with what existed before it was introduced
+    fn backfill_change_log(&self) {
+        let empty = self.conn.query("SELECT 1 FROM change_log LIMIT 1", &[]).map(|rows| rows.is_empty()).unwrap_or(false);
+        if !empty {
+            return;
+        }
+        for mark in self.find_mark() {
+            self.log_change("mark", &mark.uid.to_string(), Some(serde_json::to_string(&mark).unwrap()));
+        }
+        for station in self.find_police_station() {
+            self.log_change("station", &station.id, Some(serde_json::to_string(&station).unwrap()));
+        }
+        let mut incidents = self.get_routes().into_iter().map(|r| r.belong).collect::<Vec<_>>();
+        incidents.sort_unstable();
+        incidents.dedup();
+        for incident in incidents {
+            self.log_routes(incident);
+        }
+    }
+
+    /// The latest change of every entity changed after `since`, oldest first.
+    pub fn find_changes(&self, since: i64) -> Vec<Change> {
+        let rows = self.conn
+            .query("SELECT * FROM (SELECT DISTINCT ON (entity, entity_id) seq, entity, entity_id, data FROM change_log
+                    WHERE seq > $1 ORDER BY entity, entity_id, seq DESC) latest ORDER BY seq", &[&since]).unwrap();
+        rows.iter().map(|row| Change {
+            seq: row.get(0),
+            entity: row.get(1),
+            entity_id: row.get(2),
+            data: row.get(3),
+        }).collect()
+    }
+
+    pub fn last_change(&self) -> i64 {
+        self.conn.query("SELECT GREATEST(COALESCE(MAX(seq), 0), (SELECT COALESCE(MAX(seq), 0) FROM change_log_horizon)) FROM change_log", &[])
+            .map(|rows| rows.get(0).get(0)).unwrap_or(0)
+    }
+
+    /// Cursors before this one may have missed a deletion trimmed from the change log.
+    pub fn change_horizon(&self) -> i64 {
+        self.conn.query("SELECT COALESCE(MAX(seq), 0) FROM change_log_horizon", &[])
+            .map(|rows| rows.get(0).get(0)).unwrap_or(0)
+    }
+
+    /// Drops the changes superseded by a later one of the same entity, which `/sync` never
+    /// returns, and deletions older than `CHANGE_LOG_RETENTION`, moving the horizon past them.
+    pub fn trim_change_log(&self) -> Result<u64> {
+        let tx = self.conn.transaction()?;
+        let superseded = tx.run("DELETE FROM change_log a USING change_log b
+                    WHERE a.entity = b.entity AND a.entity_id = b.entity_id AND a.seq < b.seq", &[])?;
+        let expired = tx.rows("DELETE FROM change_log WHERE data IS NULL AND changed_at < $1 RETURNING seq",
+                              &[&(crate::unix_timestamp() - CHANGE_LOG_RETENTION)])?
+            .iter().map(|row| row.get::<usize, i64>(0)).collect::<Vec<_>>();
+        if let Some(horizon) = expired.iter().max() {
+            tx.run("DELETE FROM change_log_horizon", &[])?;
+            tx.run("INSERT INTO change_log_horizon (seq) VALUES ($1)", &[horizon])?;
+        }
+        tx.commit()?;
+        Ok(superseded + expired.len() as u64)
+    }
+
+    // incidents used to be keyed by a serial id next to a millisecond `uid`; this moves an
+    // existing database over to uuids, keeping the transition history. Routes and saved
+    // dispatcher state are dropped, the next start dispatches the open incidents again.
+    fn migrate_incident_ids(&self) {
+        let integer_column = |table: &str, column: &str| self.conn.query("SELECT 1 FROM information_schema.columns
+                    WHERE table_name = $1 AND column_name = $2 AND data_type = 'integer'", &[&table, &column])
+            .map(|rows| !rows.is_empty()).unwrap();
+        if !integer_column("telephone_operator_data", "id") {
+            return;
+        }
+        // created empty with uuids when it predates the status workflow
+        let transitions = if integer_column("incident_transitions", "incident") { &[
+            "ALTER TABLE incident_transitions ADD COLUMN incident_id UUID",
+            "UPDATE incident_transitions t SET incident_id = m.incident_id FROM telephone_operator_data m WHERE m.id = t.incident",
+            "ALTER TABLE incident_transitions DROP COLUMN incident",
+            "ALTER TABLE incident_transitions RENAME COLUMN incident_id TO incident",
+        ][..] } else { &[][..] };
+        warn!("migrating incident ids to uuids");
+        let old_ids = self.conn.query("SELECT id FROM telephone_operator_data", &[]).unwrap()
+            .iter().map(|row| row.get::<usize, i32>(0)).collect::<Vec<_>>();
+        let old_routes = self.conn.query("SELECT DISTINCT belong FROM dispatch_routes", &[])
+            .map(|rows| rows.iter().map(|row| row.get::<usize, i32>(0)).collect::<Vec<_>>()).unwrap_or_default();
+        let tx = self.conn.transaction().unwrap();
+        tx.execute("ALTER TABLE telephone_operator_data ADD COLUMN incident_id UUID NOT NULL DEFAULT gen_random_uuid()", &[]).unwrap();
+        for sql in transitions.iter().chain([
+            "ALTER TABLE telephone_operator_data DROP CONSTRAINT telephone_operator_data_pkey",
+            "ALTER TABLE telephone_operator_data DROP COLUMN id",
+            "ALTER TABLE telephone_operator_data DROP COLUMN uid",
+            "ALTER TABLE telephone_operator_data RENAME COLUMN incident_id TO id",
+            "ALTER TABLE telephone_operator_data ADD PRIMARY KEY (id)",
+            "DELETE FROM dispatch_routes",
+            "ALTER TABLE dispatch_routes ALTER COLUMN belong TYPE UUID USING NULL",
+            "DELETE FROM pending_workloads",
+            "ALTER TABLE pending_workloads ALTER COLUMN id TYPE UUID USING NULL, ALTER COLUMN assign_id TYPE UUID USING NULL",
+            "DELETE FROM active_dispatches",
+            "ALTER TABLE active_dispatches ALTER COLUMN assign_id TYPE UUID USING NULL, ALTER COLUMN to_id TYPE UUID USING NULL",
+            "DELETE FROM dispatcher_snapshots",
+        ].iter()) {
+            tx.execute(sql, &[]).unwrap();
+        }
+        tx.commit().unwrap();
+        // /sync clients drop the old ids and pick up the incidents under their new ones
+        for id in old_ids {
+            self.log_change("mark", &id.to_string(), None);
+        }
+        for id in old_routes {
+            self.log_change("route", &id.to_string(), None);
+        }
+        for mark in self.find_mark() {
+            self.log_change("mark", &mark.uid.to_string(), Some(serde_json::to_string(&mark).unwrap()));
+        }
+    }
+
+    pub fn audit(&self, entry: &AuditEntry) {
+        if let Err(err) = self.conn.execute(
+            "INSERT INTO audit_log (actor, action, entity, entity_id, before_data, after_data, ip, logged_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+            , &[&entry.actor, &entry.action, &entry.entity, &entry.entity_id, &entry.before.as_ref().map(|v| v.to_string()),
+                &entry.after.as_ref().map(|v| v.to_string()), &entry.ip, &entry.logged_at]) {
+            warn!("unable to audit {} {} {:?} by {}: {}", entry.action, entry.entity, entry.entity_id, entry.actor, err);
+        }
+    }
+
+    /// Audit entries matching `filter`, newest first, with the total number of matches.
+    pub fn find_audit(&self, filter: &AuditFilter, limit: i64, offset: i64) -> (Vec<AuditEntry>, i64) {
+        const MATCHES: &str = "($1::VARCHAR IS NULL OR actor = $1) AND ($2::VARCHAR IS NULL OR action = $2)
+                    AND ($3::VARCHAR IS NULL OR entity = $3) AND ($4::VARCHAR IS NULL OR entity_id = $4)
+                    AND ($5::BIGINT IS NULL OR logged_at >= $5) AND ($6::BIGINT IS NULL OR logged_at < $6)";
+        let params: [&dyn types::ToSql; 6] = [&filter.actor, &filter.action, &filter.entity, &filter.entity_id, &filter.from, &filter.to];
+        let total = self.conn.query(&format!("SELECT count(*) FROM audit_log WHERE {}", MATCHES), &params)
+            .map(|rows| rows.get(0).get(0)).unwrap_or(0);
+        let rows = self.conn
+            .query(&format!("SELECT actor, action, entity, entity_id, before_data, after_data, ip, logged_at, id FROM audit_log
+                    WHERE {} ORDER BY id DESC LIMIT $7 OFFSET $8", MATCHES),
+                   &[params[0], params[1], params[2], params[3], params[4], params[5], &limit, &offset]).unwrap();
+        let json = |data: Option<String>| data.and_then(|data| serde_json::from_str(&data).ok());
+        (rows.iter().map(|row| AuditEntry {
+            id: row.get(8),
+            actor: row.get(0),
+            action: row.get(1),
+            entity: row.get(2),
+            entity_id: row.get(3),
+            before: json(row.get(4)),
+            after: json(row.get(5)),
+            ip: row.get(6),
+            logged_at: row.get(7),
+        }).collect(), total)
+    }
+
+    pub fn ping(&self) -> bool {
+        self.conn.query("SELECT 1", &[]).is_ok()
+    }
+
     pub fn try_init(&self) -> bool {
         let rows = self.conn
             .query("SELECT * FROM init_data",
                    &[]).unwrap();
+        rows.len() > 1
+    }
+
+    // creates the first administrator on an empty database, without a
+    // configured password the well-known default has to be rotated first
+    pub fn bootstrap_admin(&self, password: Option<String>) {
         let users = self.conn
             .query("SELECT * FROM user_data",
                    &[]).unwrap();
-        if rows.len() < 1 && users.len() < 1 {
-            drop(rows);
+        if users.is_empty() {
             drop(users);
+            let must_change = password.is_none();
             self.add_user(User {
                 username: "admin".to_string(),
-                passwd: crate::fast_sha256("adminadmin"), // init passwd - admin
+                passwd: crate::fast_sha256(&password.unwrap_or_else(|| "adminadmin".to_string())),
                 user_type: 1,
-            })
-        } else if rows.len() > 1 {
-            return true;
+                disabled: false,
+                must_change,
+            }).unwrap();
+            if must_change {
+                warn!("created default administrator admin/adminadmin, its password must be changed at first login");
+            }
         }
-        false
     }
 
     pub fn feed_init(&self, data: UnifiedData) {
@@ -224,92 +1031,400 @@ impl DatabaseAccess {
 }
 
 impl DatabaseAccess {
-    pub fn add_mark(&self, telephone_operator: OperatorMark) -> i32 {
-        self.conn.execute(
-            "INSERT INTO telephone_operator_data (uid, positionX, positionY, positionZ, drone, height, level, description) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) "
-            , &[&Decimal::from_u128(telephone_operator.uid).unwrap(), &telephone_operator.position.x, &telephone_operator.position.y, &telephone_operator.position.z, &telephone_operator.drone,
-                &telephone_operator.height, &telephone_operator.level, &telephone_operator.desc]).unwrap();
-        let rows = self.conn.query("SELECT id FROM telephone_operator_data WHERE uid = $1"
-                                   , &[&Decimal::from_u128(telephone_operator.uid).unwrap()]).unwrap();
-        rows.iter().collect::<Vec<_>>().first().unwrap().get(0)
+    /// Stores a new incident together with the outbox entry the dispatcher works off,
+    /// returning it with the id the database gave it and the entry.
+    pub fn add_mark(&self, telephone_operator: OperatorMark, actor: &str) -> Result<(OperatorMark, i64)> {
+        let tx = self.conn.transaction()?;
+        let rows = tx.rows(
+            "INSERT INTO telephone_operator_data (positionX, positionY, positionZ, drone, height, level, description, status, reported_at, incident_type, units) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING id::text"
+            , &[&telephone_operator.position.x, &telephone_operator.position.y, &telephone_operator.position.z, &telephone_operator.drone,
+                &telephone_operator.height, &telephone_operator.level, &telephone_operator.desc,
+                &IncidentStatus::Reported.as_str(), &crate::unix_timestamp(), &telephone_operator.kind, &telephone_operator.units])?;
+        let id = uuid_at(&rows.get(0), 0);
+        let mark = OperatorMark { uid: id, status: IncidentStatus::Reported, ..telephone_operator };
+        record_transition(&tx, id, None, IncidentStatus::Reported, None, actor)?;
+        log_change(&tx, "mark", &id.to_string(), Some(serde_json::to_string(&mark).unwrap()))?;
+        let entry = tx.rows("INSERT INTO dispatch_outbox (incident, created_at) VALUES ($1::text::uuid, $2) RETURNING id",
+                            &[&id.to_string(), &crate::unix_timestamp()])?.get(0).get(0);
+        tx.commit()?;
+        Ok((mark, entry))
+    }
+
+    /// Outbox entries not dispatched yet, oldest first.
+    pub fn pending_outbox(&self) -> Vec<(i64, Uuid)> {
+        self.conn.query("SELECT id, incident::text FROM dispatch_outbox WHERE processed_at IS NULL ORDER BY id", &[]).unwrap()
+            .iter().map(|row| (row.get(0), uuid_at(&row, 1))).collect()
+    }
+
+    pub fn outbox_pending(&self, entry: i64) -> bool {
+        self.conn.query("SELECT 1 FROM dispatch_outbox WHERE id=$1 AND processed_at IS NULL", &[&entry])
+            .map(|rows| !rows.is_empty()).unwrap_or(false)
+    }
+
+    pub fn complete_outbox(&self, entry: Option<i64>) -> Result<u64> {
+        self.conn.execute("UPDATE dispatch_outbox SET processed_at=$2 WHERE ($1::BIGINT IS NULL OR id=$1) AND processed_at IS NULL",
+                          &[&entry, &crate::unix_timestamp()])
     }
 
     pub fn find_mark(&self) -> Vec<OperatorMark> {
         let rows = self.conn
-            .query("SELECT * FROM telephone_operator_data",
-                   &[]).unwrap();
-        let marks: Vec<OperatorMark> = rows.iter().map(|row| {
-            OperatorMark {
-                uid: row.get::<usize, i32>(0) as u128,
-                position: Position {
-                    x: row.get(2),
-                    y: row.get(3),
-                    z: row.get(4),
-                },
-                drone: row.get(5),
-                height: row.get(6),
-                level: row.get(7),
-                desc: row.get(8),
-            }
-        }).collect();
-        return marks;
+            .query(&format!("SELECT {} FROM telephone_operator_data WHERE status = ANY($1) ORDER BY reported_at, id", MARK_COLUMNS),
+                   &[&OPEN_STATUSES.to_vec()]).unwrap();
+        rows.iter().map(|row| Self::mark_from_row(&row)).collect()
+    }
+
+    pub fn find_mark_by_id(&self, id: Uuid) -> Option<OperatorMark> {
+        let rows = self.conn
+            .query(&format!("SELECT {} FROM telephone_operator_data WHERE id=$1::text::uuid", MARK_COLUMNS),
+                   &[&id.to_string()]).unwrap();
+        rows.iter().map(|row| Self::mark_from_row(&row)).next()
+    }
+
+    fn mark_from_row(row: &rows::Row) -> OperatorMark {
+        OperatorMark {
+            uid: uuid_at(row, 0),
+            position: Position {
+                x: row.get(1),
+                y: row.get(2),
+                z: row.get(3),
+            },
+            drone: row.get(4),
+            height: row.get(5),
+            level: row.get(6),
+            desc: row.get(7),
+            status: IncidentStatus::parse(&row.get::<usize, String>(8)).unwrap_or_default(),
+            kind: row.get(9),
+            units: row.get(10),
+        }
+    }
+
+    /// Moves an incident from `from` to `to`; false when it is no longer in `from`.
+    pub fn transition_mark(&self, id: Uuid, from: IncidentStatus, to: IncidentStatus, reason: Option<&str>, actor: &str) -> bool {
+        let tx = self.conn.transaction().unwrap();
+        let changed = transition_mark(&tx, id, from, to, reason, actor).unwrap();
+        tx.commit().unwrap();
+        changed
+    }
+
+    pub fn find_transitions(&self, id: Uuid) -> Vec<Transition> {
+        let rows = self.conn
+            .query("SELECT from_status, to_status, reason, actor, changed_at FROM incident_transitions WHERE incident=$1::text::uuid ORDER BY id",
+                   &[&id.to_string()]).unwrap();
+        rows.iter().map(|row| Transition {
+            from: row.get::<usize, Option<String>>(0).and_then(|s| IncidentStatus::parse(&s)),
+            to: IncidentStatus::parse(&row.get::<usize, String>(1)).unwrap_or_default(),
+            reason: row.get(2),
+            actor: row.get(3),
+            changed_at: row.get(4),
+        }).collect()
     }
 
-    pub fn delete_mark(&self, uid: i32) -> bool {
-        self.conn.execute("DELETE FROM telephone_operator_data WHERE id=$1"
-                          , &[&uid]).is_ok()
+    pub fn update_mark(&self, mark: &OperatorMark) -> bool {
+        let updated = self.conn.execute(
+            "UPDATE telephone_operator_data SET positionX=$2, positionY=$3, positionZ=$4, drone=$5, height=$6, level=$7, description=$8, incident_type=$9, units=$10 WHERE id=$1::text::uuid"
+            , &[&mark.uid.to_string(), &mark.position.x, &mark.position.y, &mark.position.z, &mark.drone,
+                &mark.height, &mark.level, &mark.desc, &mark.kind, &mark.units]).unwrap();
+        if updated > 0 {
+            self.log_change("mark", &mark.uid.to_string(), Some(serde_json::to_string(mark).unwrap()));
+        }
+        updated > 0
+    }
+
+    pub fn count_marks(&self) -> i64 {
+        self.conn.query("SELECT count(*) FROM telephone_operator_data WHERE status = ANY($1)", &[&OPEN_STATUSES.to_vec()])
+            .map(|rows| rows.get(0).get(0)).unwrap_or(0)
     }
 }
 
 impl DatabaseAccess {
-    pub fn add_police_station(&self, police_station: PoliceStation) {
+    pub fn add_incident_type(&self, kind: &IncidentType) -> Result<u64> {
+        self.conn.execute(
+            "INSERT INTO incident_types (code, name, priority, units, unit_types) VALUES ($1, $2, $3, $4, $5)"
+            , &[&kind.code, &kind.name, &kind.priority, &kind.units, &kind.unit_types])
+    }
+
+    pub fn update_incident_type(&self, kind: &IncidentType) -> bool {
         self.conn.execute(
-            "INSERT INTO police_station_data (uid, name, positionX, positionY, positionZ, crew, drone) VALUES ($1, $2, $3, $4, $5, $6, $7) "
+            "UPDATE incident_types SET name=$2, priority=$3, units=$4, unit_types=$5 WHERE code=$1"
+            , &[&kind.code, &kind.name, &kind.priority, &kind.units, &kind.unit_types]).unwrap() > 0
+    }
+
+    pub fn delete_incident_type(&self, code: &str) -> bool {
+        self.conn.execute("DELETE FROM incident_types WHERE code=$1", &[&code]).unwrap() > 0
+    }
+
+    pub fn find_incident_types(&self) -> Vec<IncidentType> {
+        let rows = self.conn
+            .query("SELECT code, name, priority, units, unit_types FROM incident_types ORDER BY priority DESC, code", &[]).unwrap();
+        rows.iter().map(|row| IncidentType {
+            code: row.get(0),
+            name: row.get(1),
+            priority: row.get(2),
+            units: row.get(3),
+            unit_types: row.get(4),
+        }).collect()
+    }
+
+    pub fn find_incident_type(&self, code: &str) -> Option<IncidentType> {
+        self.find_incident_types().into_iter().find(|kind| kind.code == code)
+    }
+
+    /// Stores a station with its crew and `drones` on duty drones.
+    pub fn add_police_station(&self, police_station: PoliceStation, crew: &[CrewMember]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.run(
+            "INSERT INTO police_station_data (uid, name, positionX, positionY, positionZ) VALUES ($1, $2, $3, $4, $5) "
             , &[&police_station.id, &police_station.name,
                 &police_station.position.x,
                 &police_station.position.y,
-                &police_station.position.z,
-                &police_station.crew, &police_station.drones]).unwrap();
+                &police_station.position.z])?;
+        for member in crew {
+            insert_crew(&tx, member)?;
+        }
+        for index in 0..police_station.drones.max(0) {
+            insert_equipment(&tx, &Equipment {
+                id: format!("{}-drone-{}", police_station.id, index + 1),
+                station: police_station.id.clone(),
+                kind: EquipmentKind::Drone,
+                status: DutyStatus::OnDuty,
+                capabilities: vec![],
+                shift_start: None,
+                shift_end: None,
+            })?;
+        }
+        tx.commit()?;
+        self.log_station(&police_station.id);
+        Ok(())
     }
 
+    pub fn update_police_station(&self, id: &str, name: &str, position: Position) -> bool {
+        let updated = self.conn.execute(
+            "UPDATE police_station_data SET name=$2, positionX=$3, positionY=$4, positionZ=$5 WHERE uid=$1"
+            , &[&id, &name, &position.x, &position.y, &position.z]).unwrap() > 0;
+        if updated {
+            self.log_station(id);
+        }
+        updated
+    }
+
+    // crew names and the number of drones are read from the crew and equipment tables
     pub fn find_police_station(&self) -> Vec<PoliceStation> {
         let rows = self.conn
-            .query("SELECT * FROM police_station_data",
-                   &[]).unwrap();
+            .query("SELECT p.uid, p.name, p.positionX, p.positionY, p.positionZ,
+                        COALESCE((SELECT array_agg(c.name ORDER BY c.id) FROM crew_members c WHERE c.station = p.uid), '{}'),
+                        (SELECT count(*)::INT FROM station_equipment e WHERE e.station = p.uid AND e.kind = $1)
+                    FROM police_station_data p ORDER BY p.id",
+                   &[&EquipmentKind::Drone.as_str()]).unwrap();
         let police_station: Vec<PoliceStation> = rows.iter().map(|row| {
             PoliceStation {
-                id: row.get(1),
-                name: row.get(2),
+                id: row.get(0),
+                name: row.get(1),
                 position: Position {
-                    x: row.get(3),
-                    y: row.get(4),
-                    z: row.get(5),
+                    x: row.get(2),
+                    y: row.get(3),
+                    z: row.get(4),
                 },
-                crew: row.get(6),
-                drones: row.get(7),
+                crew: row.get(5),
+                drones: row.get(6),
             }
         }).collect();
-        return police_station;
+        police_station
+    }
+
+    // the station's crew and drones show up in its /sync entry
+    fn log_station(&self, id: &str) {
+        if let Some(station) = self.find_police_station().into_iter().find(|station| station.id == id) {
+            self.log_change("station", id, Some(serde_json::to_string(&station).unwrap()));
+        }
     }
 
     pub fn delete_police_station(&self, id: String) -> bool {
-        self.conn.execute("DELETE FROM police_station_data WHERE uid=$1"
-                          , &[&id]).is_ok()
+        match self.remove_station(&id) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("unable to delete station {}: {}", id, err);
+                false
+            }
+        }
+    }
+
+    // the station goes together with its crew, equipment and shifts, or not at all
+    fn remove_station(&self, id: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if tx.run("DELETE FROM police_station_data WHERE uid=$1", &[&id])? > 0 {
+            tx.run("DELETE FROM crew_members WHERE station=$1", &[&id])?;
+            tx.run("DELETE FROM station_equipment WHERE station=$1", &[&id])?;
+            tx.run("DELETE FROM station_shifts WHERE station=$1", &[&id])?;
+            log_change(&tx, "station", id, None)?;
+        }
+        tx.commit()
     }
 }
 
 
+fn insert_crew(conn: &dyn Statements, member: &CrewMember) -> Result<u64> {
+    conn.run("INSERT INTO crew_members (id, station, name, status, capabilities, shift_start, shift_end) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+             , &[&member.id, &member.station, &member.name, &member.status.as_str(), &member.capabilities,
+                 &member.shift_start.map(ClockTime::minutes), &member.shift_end.map(ClockTime::minutes)])
+}
+
+fn insert_equipment(conn: &dyn Statements, equipment: &Equipment) -> Result<u64> {
+    conn.run("INSERT INTO station_equipment (id, station, kind, status, capabilities, shift_start, shift_end) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+             , &[&equipment.id, &equipment.station, &equipment.kind.as_str(), &equipment.status.as_str(), &equipment.capabilities,
+                 &equipment.shift_start.map(ClockTime::minutes), &equipment.shift_end.map(ClockTime::minutes)])
+}
+
+impl DatabaseAccess {
+    pub fn add_crew(&self, member: &CrewMember) -> Result<u64> {
+        let added = insert_crew(&self.conn, member)?;
+        self.log_station(&member.station);
+        Ok(added)
+    }
+
+    // `previous` is the station it belonged to, which lists it no more after a move
+    pub fn update_crew(&self, previous: &str, member: &CrewMember) -> bool {
+        let updated = self.conn.execute(
+            "UPDATE crew_members SET station=$2, name=$3, status=$4, capabilities=$5, shift_start=$6, shift_end=$7 WHERE id=$1"
+            , &[&member.id, &member.station, &member.name, &member.status.as_str(), &member.capabilities,
+                &member.shift_start.map(ClockTime::minutes), &member.shift_end.map(ClockTime::minutes)]).unwrap() > 0;
+        if updated {
+            self.log_station(&member.station);
+            if previous != member.station {
+                self.log_station(previous);
+            }
+        }
+        updated
+    }
+
+    pub fn delete_crew(&self, member: &CrewMember) -> bool {
+        let deleted = self.conn.execute("DELETE FROM crew_members WHERE id=$1", &[&member.id]).unwrap() > 0;
+        if deleted {
+            self.log_station(&member.station);
+        }
+        deleted
+    }
+
+    pub fn find_crew(&self, station: Option<&str>) -> Vec<CrewMember> {
+        let rows = self.conn
+            .query("SELECT id, station, name, status, capabilities, shift_start, shift_end FROM crew_members
+                    WHERE $1::VARCHAR IS NULL OR station=$1 ORDER BY station, id", &[&station]).unwrap();
+        rows.iter().map(|row| CrewMember {
+            id: row.get(0),
+            station: row.get(1),
+            name: row.get(2),
+            status: DutyStatus::parse(&row.get::<usize, String>(3)).unwrap_or_default(),
+            capabilities: row.get(4),
+            shift_start: row.get::<usize, Option<i32>>(5).map(ClockTime::from_minutes),
+            shift_end: row.get::<usize, Option<i32>>(6).map(ClockTime::from_minutes),
+        }).collect()
+    }
+
+    /// Crew and drones on duty at every station, leaving out those whose own shift does not
+    /// cover `minute` (minutes since local midnight); a shift ending at or before its start runs overnight.
+    pub fn find_on_duty_units(&self, minute: i32) -> Vec<(String, usize, usize)> {
+        self.conn.query("SELECT p.uid,
+                            (SELECT count(*) FROM crew_members c WHERE c.station = p.uid AND c.status = $1 AND (c.shift_start IS NULL
+                                OR CASE WHEN c.shift_start < c.shift_end THEN $3 >= c.shift_start AND $3 < c.shift_end
+                                        ELSE $3 >= c.shift_start OR $3 < c.shift_end END)),
+                            (SELECT count(*) FROM station_equipment e WHERE e.station = p.uid AND e.kind = $2 AND e.status = $1 AND (e.shift_start IS NULL
+                                OR CASE WHEN e.shift_start < e.shift_end THEN $3 >= e.shift_start AND $3 < e.shift_end
+                                        ELSE $3 >= e.shift_start OR $3 < e.shift_end END))
+                        FROM police_station_data p", &[&DutyStatus::OnDuty.as_str(), &EquipmentKind::Drone.as_str(), &minute]).unwrap()
+            .iter().map(|row| (row.get(0), row.get::<usize, i64>(1) as usize, row.get::<usize, i64>(2) as usize)).collect()
+    }
+
+    /// Start and end times of the crew and equipment shifts, where units come and go.
+    pub fn find_unit_shift_times(&self) -> Vec<ClockTime> {
+        self.conn.query("SELECT shift_start FROM crew_members WHERE shift_start IS NOT NULL
+                        UNION SELECT shift_end FROM crew_members WHERE shift_end IS NOT NULL
+                        UNION SELECT shift_start FROM station_equipment WHERE shift_start IS NOT NULL
+                        UNION SELECT shift_end FROM station_equipment WHERE shift_end IS NOT NULL", &[]).unwrap()
+            .iter().map(|row| ClockTime::from_minutes(row.get(0))).collect()
+    }
+
+    pub fn find_shifts(&self, station: Option<&str>) -> Vec<Shift> {
+        let rows = self.conn
+            .query("SELECT station, weekday, shift_start, shift_end, crew, drones FROM station_shifts
+                    WHERE $1::VARCHAR IS NULL OR station=$1 ORDER BY station, weekday, shift_start", &[&station]).unwrap();
+        rows.iter().map(|row| Shift {
+            station: row.get(0),
+            weekday: row.get(1),
+            start: ClockTime::from_minutes(row.get(2)),
+            end: ClockTime::from_minutes(row.get(3)),
+            crew: row.get(4),
+            drones: row.get(5),
+        }).collect()
+    }
+
+    pub fn replace_shifts(&self, station: &str, shifts: &[Shift]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.run("DELETE FROM station_shifts WHERE station=$1", &[&station])?;
+        for shift in shifts {
+            tx.run("INSERT INTO station_shifts (station, weekday, shift_start, shift_end, crew, drones) VALUES ($1, $2, $3, $4, $5, $6)"
+                   , &[&station, &shift.weekday, &shift.start.minutes(), &shift.end.minutes(), &shift.crew, &shift.drones])?;
+        }
+        tx.commit()
+    }
+
+    pub fn add_equipment(&self, equipment: &Equipment) -> Result<u64> {
+        let added = insert_equipment(&self.conn, equipment)?;
+        self.log_station(&equipment.station);
+        Ok(added)
+    }
+
+    // `previous` is the station it belonged to, which lists it no more after a move
+    pub fn update_equipment(&self, previous: &str, equipment: &Equipment) -> bool {
+        let updated = self.conn.execute(
+            "UPDATE station_equipment SET station=$2, kind=$3, status=$4, capabilities=$5, shift_start=$6, shift_end=$7 WHERE id=$1"
+            , &[&equipment.id, &equipment.station, &equipment.kind.as_str(), &equipment.status.as_str(), &equipment.capabilities,
+                &equipment.shift_start.map(ClockTime::minutes), &equipment.shift_end.map(ClockTime::minutes)]).unwrap() > 0;
+        if updated {
+            self.log_station(&equipment.station);
+            if previous != equipment.station {
+                self.log_station(previous);
+            }
+        }
+        updated
+    }
+
+    pub fn delete_equipment(&self, equipment: &Equipment) -> bool {
+        let deleted = self.conn.execute("DELETE FROM station_equipment WHERE id=$1", &[&equipment.id]).unwrap() > 0;
+        if deleted {
+            self.log_station(&equipment.station);
+        }
+        deleted
+    }
+
+    pub fn find_equipment(&self, station: Option<&str>) -> Vec<Equipment> {
+        let rows = self.conn
+            .query("SELECT id, station, kind, status, capabilities, shift_start, shift_end FROM station_equipment
+                    WHERE $1::VARCHAR IS NULL OR station=$1 ORDER BY station, id", &[&station]).unwrap();
+        rows.iter().map(|row| Equipment {
+            id: row.get(0),
+            station: row.get(1),
+            kind: EquipmentKind::parse(&row.get::<usize, String>(2)).unwrap_or(EquipmentKind::Vehicle),
+            status: DutyStatus::parse(&row.get::<usize, String>(3)).unwrap_or_default(),
+            capabilities: row.get(4),
+            shift_start: row.get::<usize, Option<i32>>(5).map(ClockTime::from_minutes),
+            shift_end: row.get::<usize, Option<i32>>(6).map(ClockTime::from_minutes),
+        }).collect()
+    }
+}
+
 impl DatabaseAccess {
     pub fn add_login(&self, user: LoginInfo) {
+        let now = crate::unix_timestamp();
         self.conn.execute(
-            "INSERT INTO login_data (name, token, type) VALUES ($1, $2, $3) "
-            , &[&user.username, &user.token, &user.user_type]).unwrap();
+            "INSERT INTO login_data (name, token, type, created_at, last_seen, expires_at) VALUES ($1, $2, $3, $4, $4, $5) "
+            , &[&user.username, &user.token, &user.user_type, &now, &(now + self.session_ttl)]).unwrap();
     }
 
+    // looking up a session also slides its expiry forward
     pub fn find_login(&self, token: String) -> Option<LoginInfo> {
+        let now = crate::unix_timestamp();
         let rows = self.conn
-            .query("SELECT * FROM login_data WHERE token=$1",
-                   &[&token]).unwrap();
+            .query("UPDATE login_data SET last_seen=$2, expires_at=$3 WHERE token=$1 AND expires_at > $2 RETURNING *",
+                   &[&token, &now, &(now + self.session_ttl)]).unwrap();
         let info: Vec<LoginInfo> = rows.iter().map(|row| {
             LoginInfo {
                 username: row.get(2),
@@ -317,15 +1432,95 @@ impl DatabaseAccess {
                 user_type: row.get(3),
             }
         }).collect();
-        return info.first().map(|u| u.clone());
+        info.first().cloned()
+    }
+
+    // like find_login, but without extending the session
+    pub fn session_active(&self, token: &str) -> bool {
+        self.conn.query("SELECT 1 FROM login_data WHERE token=$1 AND expires_at > $2", &[&token, &crate::unix_timestamp()])
+            .map(|rows| !rows.is_empty()).unwrap_or(false)
     }
 
     pub fn logout(&self, token: String) -> bool {
         self.conn.execute("DELETE FROM login_data WHERE token=$1"
                           , &[&token]).is_ok()
     }
+
+    pub fn revoke_sessions(&self, username: &str) -> u64 {
+        self.conn.execute("DELETE FROM login_data WHERE name=$1"
+                          , &[&username]).unwrap_or(0)
+    }
+
+    pub fn sweep_sessions(&self) -> u64 {
+        self.conn.execute("DELETE FROM login_data WHERE expires_at <= $1"
+                          , &[&crate::unix_timestamp()]).unwrap_or(0)
+    }
+
+    pub fn find_sessions(&self) -> Vec<Session> {
+        let rows = self.conn
+            .query("SELECT * FROM login_data WHERE expires_at > $1 ORDER BY last_seen DESC",
+                   &[&crate::unix_timestamp()]).unwrap();
+        rows.iter().map(|row| {
+            Session {
+                id: row.get(0),
+                username: row.get(2),
+                user_type: row.get(3),
+                created_at: row.get(4),
+                last_seen: row.get(5),
+                expires_at: row.get(6),
+            }
+        }).collect()
+    }
 }
 
+impl DatabaseAccess {
+    pub fn find_login_lock(&self, key: &str, now: i64) -> Option<i64> {
+        let rows = self.conn
+            .query("SELECT locked_until FROM login_attempts WHERE key=$1 AND locked_until > $2",
+                   &[&key, &now]).unwrap();
+        rows.iter().map(|row| row.get(0)).next()
+    }
+
+    // returns the number of failures within the window, this one included
+    pub fn record_login_failure(&self, key: &str, now: i64, window: i64) -> i32 {
+        let rows = self.conn
+            .query("INSERT INTO login_attempts (key, failures, last_failure) VALUES ($1, 1, $2)
+                    ON CONFLICT (key) DO UPDATE SET
+                    failures = CASE WHEN login_attempts.last_failure > $2 - $3 THEN login_attempts.failures + 1 ELSE 1 END,
+                    last_failure = $2
+                    RETURNING failures",
+                   &[&key, &now, &window]).unwrap();
+        rows.iter().map(|row| row.get(0)).next().unwrap_or(1)
+    }
+
+    pub fn lock_login(&self, key: &str, failures: i32, until: i64, ip: &str) {
+        self.conn.execute("UPDATE login_attempts SET locked_until=$2 WHERE key=$1"
+                          , &[&key, &until]).unwrap();
+        self.conn.execute(
+            "INSERT INTO login_lockouts (key, failures, ip, created_at, locked_until) VALUES ($1, $2, $3, $4, $5)"
+            , &[&key, &failures, &ip, &crate::unix_timestamp(), &until]).unwrap();
+    }
+
+    pub fn clear_login_failures(&self, key: &str) {
+        self.conn.execute("DELETE FROM login_attempts WHERE key=$1"
+                          , &[&key]).unwrap();
+    }
+
+    pub fn find_lockouts(&self) -> Vec<Lockout> {
+        let rows = self.conn
+            .query("SELECT * FROM login_lockouts ORDER BY created_at DESC",
+                   &[]).unwrap();
+        rows.iter().map(|row| {
+            Lockout {
+                key: row.get(1),
+                failures: row.get(2),
+                ip: row.get(3),
+                created_at: row.get(4),
+                locked_until: row.get(5),
+            }
+        }).collect()
+    }
+}
 
 impl Default for DatabaseAccess {
     fn default() -> Self {
@@ -2,12 +2,16 @@ use std::sync::{Arc, Mutex};
 use binary_heap_plus::BinaryHeap;
 use json::JsonValue;
 use std::sync::atomic::AtomicUsize;
-use crate::database::Position;
+use crate::database::{OperatorMark, Position};
+use log::debug;
+use crate::metrics::ROUTE_COMPUTATION;
+use uuid::Uuid;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Coordinates {
     pub x: f64,
     pub y: f64,
+    #[allow(dead_code)]
     pub h: f64,
 }
 
@@ -21,7 +25,7 @@ impl From<Position> for Coordinates {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RoadIntersection {
     id: usize,
     location: Coordinates,
@@ -39,10 +43,14 @@ impl RoadIntersection {
     fn compute_distance(&self, other: &Self) -> f64 {
         self.location.compute_distance(&other.location)
     }
+
+    fn point(&self) -> (f64, f64) {
+        (self.location.x, self.location.y)
+    }
 }
 
 impl Coordinates {
-    fn compute_distance(&self, other: &Self) -> f64 {
+    pub fn compute_distance(&self, other: &Self) -> f64 {
         ((self.x - other.x).powi(2)
             + (self.y - other.y).powi(2))
             .sqrt()
@@ -53,8 +61,8 @@ type Path = Vec<(usize, usize)>;
 
 type RoadGraph = Vec<RoadIntersection>;
 
-pub fn construct_topology(points: &Vec<RawPoint>) -> RoadGraph {
-    let mut bound = points.iter().zip(0..points.len()).map(|(p, id)| RoadIntersection {
+pub fn construct_topology(points: &[RawPoint]) -> RoadGraph {
+    let mut bound = points.iter().enumerate().map(|(id, p)| RoadIntersection {
         location: p.location,
         id,
         link_to: vec![],
@@ -62,27 +70,27 @@ pub fn construct_topology(points: &Vec<RawPoint>) -> RoadGraph {
     for pos in 0..bound.len() {
         let info = &points[pos];
         let pos = &mut bound[pos];
-        let vec1 = points.iter().zip(0..points.len())
-            .filter(|(p, id)| *id != pos.id && (p.r1 == info.r1 || p.r2 == info.r1) && !pos.link_to.contains(id))
+        let vec1 = points.iter().enumerate()
+            .filter(|(id, p)| *id != pos.id && (p.r1 == info.r1 || p.r2 == info.r1) && !pos.link_to.contains(id))
             .collect::<Vec<_>>();
         for p in vec1.iter() {
-            pos.link_to.push(p.1);
+            pos.link_to.push(p.0);
         }
         if info.r2 >= 0 {
             // try-connect policy - connect two more times
-            let vec2 = points.iter().zip(0..points.len())
-                .filter(|(p, id)| *id != pos.id && (p.r1 == info.r2 || p.r2 == info.r1 || p.r2 == info.r2) && !pos.link_to.contains(id))
+            let vec2 = points.iter().enumerate()
+                .filter(|(id, p)| *id != pos.id && (p.r1 == info.r2 || p.r2 == info.r1 || p.r2 == info.r2) && !pos.link_to.contains(id))
                 .collect::<Vec<_>>();
             for p in vec2.iter() {
-                pos.link_to.push(p.1);
+                pos.link_to.push(p.0);
             }
         }
     }
     bound
 }
 
-pub fn parse_road_data(geojson: &String) -> Result<Vec<RawPoint>, ()> {
-    let object = json::parse(&geojson[..]).expect("failed to parse json");
+pub fn parse_road_data(geojson: &str) -> Result<Vec<RawPoint>, ()> {
+    let object = json::parse(geojson).expect("failed to parse json");
     if let JsonValue::Object(object) = object {
         if let Some(JsonValue::Array(features)) = object.get("features") {
             return Ok(features.iter().filter_map(|v| {
@@ -115,7 +123,7 @@ pub fn parse_road_data(geojson: &String) -> Result<Vec<RawPoint>, ()> {
 
 #[test]
 fn test_road_parse() {
-    let roadmap = parse_road_data(&include_str!("../graph_test.geojson").to_string()).unwrap();
+    let roadmap = parse_road_data(include_str!("../graph_test.geojson")).unwrap();
     let graph = construct_topology(&roadmap);
     let optimized = offline_bellman_ford(&graph);
     // ensure that all data are properly mapped
@@ -123,6 +131,15 @@ fn test_road_parse() {
     assert!(dbg!(blank) <= optimized.len());
 }
 
+// a dispatcher over the test road network
+#[cfg(test)]
+fn test_dispatcher() -> Arc<Mutex<Dispatcher>> {
+    let roadmap = parse_road_data(include_str!("../graph_test.geojson")).unwrap();
+    let graph = construct_topology(&roadmap);
+    let optimized = offline_bellman_ford(&graph);
+    Dispatcher::new(graph, optimized, DISPATCH_FACTOR)
+}
+
 pub fn offline_bellman_ford(graph: &RoadGraph) -> Vec<Vec<Path>> {
     graph.iter().map(|pos| {
         let mut queue = BinaryHeap::with_capacity_by(graph.len(), |&(_, u): &(usize, f64), &(_, v): &(usize, f64)| {
@@ -137,7 +154,7 @@ pub fn offline_bellman_ford(graph: &RoadGraph) -> Vec<Vec<Path>> {
             if i == pos.id {
                 nearest.push(0.0)
             } else {
-                nearest.push(std::f64::MAX)
+                nearest.push(f64::MAX)
             }
         }
         visited[pos.id] = true;
@@ -165,24 +182,66 @@ pub fn offline_bellman_ford(graph: &RoadGraph) -> Vec<Vec<Path>> {
 
 #[derive(Clone)]
 pub struct Drone {
+    // free officers
     pub power: usize,
+    // free drones, sent only to incidents taking them
+    pub drones: usize,
     pub location: Coordinates,
     pub uid: String,
 }
 
+impl Drone {
+    // units the station can send to an incident taking drones or not
+    pub fn free(&self, drone: bool) -> usize {
+        self.power + if drone { self.drones } else { 0 }
+    }
+
+    // takes up to `units` units, officers first, returning the units and the drones among them
+    pub fn take(&mut self, units: usize, drone: bool) -> (usize, usize) {
+        let officers = units.min(self.power);
+        let drones = if drone { (units - officers).min(self.drones) } else { 0 };
+        self.power -= officers;
+        self.drones -= drones;
+        (officers + drones, drones)
+    }
+
+    // units coming back from a dispatch
+    pub fn restore(&mut self, dispatch: &Dispatch) {
+        self.power += dispatch.power - dispatch.drones;
+        self.drones += dispatch.drones;
+    }
+}
+
 #[derive(Clone)]
 pub struct Workload {
     pub is_remove: bool,
-    pub id: usize,
+    pub id: Uuid,
     pub severity: usize,
     pub consumption: usize,
     pub location: Coordinates,
-    pub assign_id: usize,
+    pub assign_id: Uuid,
+    // whether drones may be sent besides officers
     pub drone: bool,
+    // stations a dispatcher pinned the incident away from
+    pub excluded: Vec<String>,
 }
 
 impl Workload {
-    pub fn delete(assign: usize) -> Self {
+    // severity is the incident's level (its type's priority), it needs the units its type requires
+    pub fn for_mark(mark: &OperatorMark) -> Self {
+        Self {
+            is_remove: false,
+            id: mark.uid,
+            assign_id: mark.uid,
+            severity: mark.level as usize,
+            consumption: mark.units.max(0) as usize,
+            location: Coordinates::from(mark.position),
+            drone: mark.drone,
+            excluded: vec![],
+        }
+    }
+
+    pub fn delete(assign: Uuid) -> Self {
         Self {
             is_remove: true,
             id: assign,
@@ -195,6 +254,7 @@ impl Workload {
             },
             assign_id: assign,
             drone: false,
+            excluded: vec![],
         }
     }
 }
@@ -203,17 +263,46 @@ impl Workload {
 pub struct Dispatch {
     pub id: usize,
     pub power: usize,
+    // drones among the units
+    pub drones: usize,
     pub severity: usize,
     pub location: Coordinates,
-    pub assign: usize,
+    pub assign: Uuid,
     pub source: String,
-    pub to_id: usize,
+    pub to_id: Uuid,
+}
+
+impl Dispatch {
+    // units that could be taken over by an incident taking drones or not
+    fn movable(&self, drone: bool) -> usize {
+        if drone { self.power } else { self.power - self.drones }
+    }
+
+    // hands over up to `units` units, officers first, returning the units and the drones among them
+    fn take(&mut self, units: usize, drone: bool) -> (usize, usize) {
+        let officers = units.min(self.power - self.drones);
+        let drones = if drone { (units - officers).min(self.drones) } else { 0 };
+        self.power -= officers + drones;
+        self.drones -= drones;
+        (officers + drones, drones)
+    }
+
+    // sends up to `units` units home, drones first, returning them as a dispatch of their own
+    pub fn release(&mut self, units: usize) -> Dispatch {
+        let drones = units.min(self.drones);
+        let officers = (units - drones).min(self.power - self.drones);
+        self.power -= officers + drones;
+        self.drones -= drones;
+        Dispatch { power: officers + drones, drones, ..self.clone() }
+    }
 }
 
 #[derive(Clone)]
 pub struct Mission {
+    #[allow(dead_code)]
     pub id: usize,
     pub power: usize,
+    pub drones: usize,
     pub severity: usize,
     pub from: Coordinates,
     pub to: Coordinates,
@@ -222,53 +311,332 @@ pub struct Mission {
     pub source: String,
 }
 
-pub struct Dispatcher(RoadGraph, Vec<Vec<Path>>);
+/// The roads within `reach` metres of driving from a set of sources.
+pub struct Isochrone {
+    pub reach: f64,
+    // road segments driven along, cut where the reach runs out
+    pub roads: Vec<Vec<(f64, f64)>>,
+    // convex hull of the roads as a closed ring, empty while they span no area
+    pub outline: Vec<(f64, f64)>,
+}
+
+pub struct Dispatcher(RoadGraph, Vec<Vec<Path>>, f64);
 
-const DISPATCH_FACTOR: f64 = 3f64;
+/// A copy of the road intersections, searched without holding the dispatcher.
+pub struct RoadNetwork(RoadGraph);
+
+impl RoadNetwork {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // the intersection closest to `at` with the straight distance to it in metres
+    fn entry(&self, at: Coordinates) -> Option<(usize, f64)> {
+        self.0.iter().min_by(|v1, v2| at.compute_distance(&v1.location).partial_cmp(&at.compute_distance(&v2.location)).unwrap())
+            .map(|v| (v.id, route_length(&[(at.x, at.y), v.point()])))
+    }
+
+    // metres of driving from the closest of the sources to every intersection, infinite where
+    // none leads; units first go straight to the intersection nearest to them
+    fn travel_distances(&self, sources: &[Coordinates]) -> Vec<f64> {
+        let mut nearest = vec![f64::INFINITY; self.0.len()];
+        let mut queue = BinaryHeap::with_capacity_by(self.0.len(), |&(_, u): &(usize, f64), &(_, v): &(usize, f64)| {
+            v.partial_cmp(&u).unwrap()
+        });
+        for (entry, access) in sources.iter().filter_map(|source| self.entry(*source)) {
+            if access < nearest[entry] {
+                nearest[entry] = access;
+                queue.push((entry, access));
+            }
+        }
+        while let Some((at, distance)) = queue.pop() {
+            if distance > nearest[at] {
+                continue;
+            }
+            let cur = &self.0[at];
+            for &to in cur.link_to.iter() {
+                let next = distance + route_length(&[cur.point(), self.0[to].point()]);
+                if next < nearest[to] {
+                    nearest[to] = next;
+                    queue.push((to, next));
+                }
+            }
+        }
+        nearest
+    }
+
+    /// The part of the road network units leaving any of `sources` reach within each of
+    /// `reaches` metres; the closest source wins where their areas meet. The outline is the
+    /// convex hull of the roads reached, larger than the area covered where the network is concave.
+    pub fn isochrones(&self, sources: &[Coordinates], reaches: &[f64]) -> Vec<Isochrone> {
+        let nearest = self.travel_distances(sources);
+        let mut links = self.0.iter()
+            .flat_map(|v| v.link_to.iter().map(move |&to| (v.id.min(to), v.id.max(to))))
+            .collect::<Vec<_>>();
+        links.sort_unstable();
+        links.dedup();
+        reaches.iter().map(|&reach| {
+            let mut roads = sources.iter().filter_map(|source| {
+                let (entry, _) = self.entry(*source)?;
+                let from = (source.x, source.y);
+                Some(vec![from, along(from, self.0[entry].point(), reach)])
+            }).filter(|_| reach > 0f64).collect::<Vec<_>>();
+            for &(u, v) in links.iter() {
+                let (from, to) = (self.0[u].point(), self.0[v].point());
+                // how far along the road units get from either end
+                let (ahead, back) = (reach - nearest[u], reach - nearest[v]);
+                if ahead.max(0f64) + back.max(0f64) >= route_length(&[from, to]) && (ahead >= 0f64 || back >= 0f64) {
+                    roads.push(vec![from, to]);
+                    continue;
+                }
+                if ahead > 0f64 {
+                    roads.push(vec![from, along(from, to, ahead)]);
+                }
+                if back > 0f64 {
+                    roads.push(vec![to, along(to, from, back)]);
+                }
+            }
+            let outline = convex_hull(roads.iter().flatten().cloned().collect());
+            Isochrone { reach, roads, outline }
+        }).collect()
+    }
+}
+
+pub const DISPATCH_FACTOR: f64 = 3f64;
+
+// km/h
+pub const DISPATCH_SPEED: f64 = 40f64;
+
+// share of the road network a station must lead to and be reached from
+pub const STATION_REACH: f64 = 0.5;
+
+// metres along a route of longitude / latitude points, flat earth is close enough at city scale
+pub fn route_length(route: &[(f64, f64)]) -> f64 {
+    const METRES_PER_DEGREE: f64 = 111_320f64;
+    route.windows(2).map(|leg| {
+        let (from, to) = (leg[0], leg[1]);
+        let dx = (to.0 - from.0) * ((from.1 + to.1) / 2f64).to_radians().cos();
+        let dy = to.1 - from.1;
+        (dx * dx + dy * dy).sqrt() * METRES_PER_DEGREE
+    }).sum()
+}
+
+// the point `at` metres from `from` on the straight way to `to`
+fn along(from: (f64, f64), to: (f64, f64), at: f64) -> (f64, f64) {
+    let length = route_length(&[from, to]);
+    if length <= 0f64 {
+        return from;
+    }
+    let part = (at / length).clamp(0f64, 1f64);
+    (from.0 + (to.0 - from.0) * part, from.1 + (to.1 - from.1) * part)
+}
+
+// monotone chain, the ring ends where it starts; empty for fewer than three points off a line
+fn convex_hull(mut points: Vec<(f64, f64)>) -> Vec<(f64, f64)> {
+    points.sort_by(|p1, p2| p1.partial_cmp(p2).unwrap());
+    points.dedup();
+    let turn = |o: (f64, f64), a: (f64, f64), b: (f64, f64)| (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+    let mut hull: Vec<(f64, f64)> = Vec::with_capacity(points.len() + 1);
+    for &p in points.iter() {
+        while hull.len() >= 2 && turn(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0f64 {
+            hull.pop();
+        }
+        hull.push(p);
+    }
+    let lower = hull.len() + 1;
+    for &p in points.iter().rev().skip(1) {
+        while hull.len() >= lower && turn(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0f64 {
+            hull.pop();
+        }
+        hull.push(p);
+    }
+    if hull.len() < 4 {
+        return vec![];
+    }
+    hull
+}
+
+#[test]
+fn test_isochrones() {
+    let dispatcher = test_dispatcher();
+    let dispatcher = dispatcher.lock().unwrap();
+    let (min, max) = dispatcher.bounds().unwrap();
+    let centre = Coordinates { x: (min.x + max.x) / 2f64, y: (min.y + max.y) / 2f64, h: 0.0 };
+    let driven = |isochrone: &Isochrone| isochrone.roads.iter().map(|road| route_length(road)).sum::<f64>();
+    let network = dispatcher.road_network();
+    let bands = network.isochrones(&[centre], &[0f64, 500f64, 1500f64, 1e7]);
+    assert!(bands[0].roads.is_empty() && bands[0].outline.is_empty());
+    assert!(bands.windows(2).all(|pair| driven(&pair[0]) <= driven(&pair[1])));
+    assert!(driven(&bands[1]) > 0f64);
+    assert!(bands[2].outline.len() >= 4 && bands[2].outline.first() == bands[2].outline.last());
+    // everything inside the outline of a band lies inside the outline of the next one
+    let inside = |ring: &[(f64, f64)], p: (f64, f64)| ring.windows(2)
+        .all(|edge| (edge[1].0 - edge[0].0) * (p.1 - edge[0].1) - (edge[1].1 - edge[0].1) * (p.0 - edge[0].0) >= -1e-12);
+    assert!(bands[2].roads.iter().flatten().all(|p| inside(&bands[3].outline, *p)));
+    // more stations cover more road
+    let corner = network.isochrones(&[min], &[1500f64]);
+    let both = network.isochrones(&[centre, min], &[1500f64]);
+    assert!(driven(&both[0]) >= driven(&bands[2]).max(driven(&corner[0])));
+    assert!(RoadNetwork(vec![]).isochrones(&[centre], &[1500f64])[0].roads.is_empty());
+}
+
+#[test]
+fn test_drone_units() {
+    let dispatcher = test_dispatcher();
+    let dispatcher = dispatcher.lock().unwrap();
+    let (min, max) = dispatcher.bounds().unwrap();
+    let station = Drone { power: 1, drones: 2, location: min, uid: "ps1".to_string() };
+    let mut workload = Workload::delete(Uuid::nil());
+    workload.is_remove = false;
+    workload.consumption = 3;
+    workload.location = max;
+    let id = AtomicUsize::new(1);
+    // officers only
+    let mut stations = vec![station.clone()];
+    let (missions, rest) = dispatcher.online_dispatch_round(workload.clone(), &mut [], &mut stations, &id);
+    assert_eq!((missions[0].power, missions[0].drones, rest.consumption), (1, 0, 2));
+    assert_eq!((stations[0].power, stations[0].drones), (0, 2));
+    // officers first, then drones
+    workload.drone = true;
+    let mut stations = vec![station];
+    let (missions, rest) = dispatcher.online_dispatch_round(workload, &mut [], &mut stations, &id);
+    assert_eq!((missions[0].power, missions[0].drones, rest.consumption), (3, 2, 0));
+    let mut dispatch = Dispatch { id: 2, power: 3, drones: 2, severity: 0, location: max, assign: Uuid::nil(), source: "ps1".to_string(), to_id: Uuid::nil() };
+    assert_eq!(dispatch.movable(false), 1);
+    let released = dispatch.release(2);
+    assert_eq!((released.power, released.drones, dispatch.power, dispatch.drones), (2, 2, 1, 0));
+    stations[0].restore(&released);
+    assert_eq!((stations[0].power, stations[0].drones), (0, 2));
+}
+
+#[test]
+fn test_check_station() {
+    let dispatcher = test_dispatcher();
+    let dispatcher = dispatcher.lock().unwrap();
+    let (min, max) = dispatcher.bounds().unwrap();
+    assert!(dispatcher.check_station(Coordinates { x: max.x + 1.0, y: min.y, h: 0.0 }, STATION_REACH).is_err());
+    // intersections by how much of the network they lead to and are reached from
+    let size = dispatcher.topology_size();
+    let reach = |i: usize| (dispatcher.1[i].iter().filter(|p| !p.is_empty()).count() + 1)
+        .min(dispatcher.1.iter().filter(|paths| !paths[i].is_empty()).count() + 1);
+    let hub = (0..size).max_by_key(|i| reach(*i)).unwrap();
+    let weakest = (0..size).min_by_key(|i| reach(*i)).unwrap();
+    assert!(dispatcher.check_station(dispatcher.0[hub].location, STATION_REACH).is_ok());
+    assert!(dispatcher.check_station(dispatcher.0[weakest].location, 0f64).is_ok());
+    if reach(weakest) < size {
+        assert!(dispatcher.check_station(dispatcher.0[weakest].location, 1f64).is_err());
+    }
+    assert!(Dispatcher::new(vec![], vec![], DISPATCH_FACTOR).lock().unwrap().check_station(min, STATION_REACH).is_ok());
+}
+
+#[test]
+fn test_route_length() {
+    assert_eq!(route_length(&[(116.3, 39.9)]), 0f64);
+    let north = route_length(&[(0f64, 0f64), (0f64, 0.01), (0f64, 0.02)]);
+    assert!((north - 2226.4).abs() < 0.1);
+    // a degree of longitude shrinks away from the equator
+    assert!(route_length(&[(116.3, 60f64), (116.31, 60f64)]) < route_length(&[(116.3, 0f64), (116.31, 0f64)]) * 0.51);
+}
 
 impl Dispatcher {
     // This should constantly be locked by a mutex
-    pub fn new(graph: RoadGraph, paths: Vec<Vec<Path>>) -> Arc<Mutex<Self>> {
+    pub fn new(graph: RoadGraph, paths: Vec<Vec<Path>>, factor: f64) -> Arc<Mutex<Self>> {
         Arc::new(
             Mutex::new(
-                Self(graph, paths)
+                Self(graph, paths, factor)
             )
         )
     }
 
+    // the intersections without the precomputed paths, for searches of their own
+    pub fn road_network(&self) -> RoadNetwork {
+        RoadNetwork(self.0.clone())
+    }
+
+    // number of road intersections in the loaded topology
+    pub fn topology_size(&self) -> usize {
+        self.0.len()
+    }
+
+    // south west and north east corners of the road network, none before it is loaded
+    pub fn bounds(&self) -> Option<(Coordinates, Coordinates)> {
+        let first = self.0.first()?.location;
+        Some(self.0.iter().fold((first, first), |(min, max), v| (
+            Coordinates { x: min.x.min(v.location.x), y: min.y.min(v.location.y), h: 0.0 },
+            Coordinates { x: max.x.max(v.location.x), y: max.y.max(v.location.y), h: 0.0 },
+        )))
+    }
+
+    /// Checks that units leaving a station at `at` can drive to most of the road network and
+    /// back: it must lie inside the network's bounds and its nearest intersection must lead to,
+    /// and be reached from, at least the `min_reach` share of the intersections (0 to 1).
+    /// Anything goes while no road network is loaded.
+    pub fn check_station(&self, at: Coordinates, min_reach: f64) -> Result<(), String> {
+        let (min, max) = match self.bounds() {
+            Some(bounds) => bounds,
+            None => return Ok(())
+        };
+        if at.x < min.x || at.x > max.x || at.y < min.y || at.y > max.y {
+            return Err(format!("Position ({}, {}) lies outside the road network ({}, {}) - ({}, {}) !",
+                               at.x, at.y, min.x, min.y, max.x, max.y));
+        }
+        let nearest = self.0.iter().map(|v| (at.compute_distance(&v.location), v))
+            .min_by(|v1, v2| v1.0.partial_cmp(&v2.0).unwrap())
+            .unwrap().1;
+        // counting the intersection itself
+        let leads_to = self.1[nearest.id].iter().filter(|path| !path.is_empty()).count() + 1;
+        let reached_from = self.1.iter().filter(|paths| !paths[nearest.id].is_empty()).count() + 1;
+        let enough = |count: usize| count as f64 >= min_reach * self.0.len() as f64;
+        if !enough(leads_to) {
+            return Err(format!("Station is unreachable, its nearest road intersection leads to {} of {} intersections only !",
+                               leads_to, self.0.len()));
+        }
+        if !enough(reached_from) {
+            return Err(format!("Station is a dead end, its nearest road intersection is reached from {} of {} intersections only !",
+                               reached_from, self.0.len()));
+        }
+        Ok(())
+    }
+
     // heuristic function to assess witch dispatch policy to use
-    fn assess_dispatch(dis1: f64, dis2: f64, sev: i32) -> bool {
+    fn assess_dispatch(dis1: f64, dis2: f64, sev: i32, factor: f64) -> bool {
         let sev = sev as f64;
         if dis2 <= dis1 {
             false
         } else {
             // slide the edge
-            return ((dis2 - dis1) / dis1 * (sev * DISPATCH_FACTOR / 3f64 + 1f64)).log2() > 0f64;
+            ((dis2 - dis1) / dis1 * (sev * factor / 3f64 + 1f64)).log2() > 0f64
         }
     }
 
-    fn next_sat<'x>(workload: &Workload, ongoing: &'x mut Vec<Dispatch>, resources: &'x mut Vec<Drone>) -> (usize, Option<Result<&'x mut Dispatch, &'x mut Drone>>) {
-        let dispatch = ongoing.iter_mut().filter(|v| v.severity < workload.severity && v.power > 0)
+    fn next_sat<'x>(&self, workload: &Workload, ongoing: &'x mut [Dispatch], resources: &'x mut [Drone]) -> (usize, Option<Result<&'x mut Dispatch, &'x mut Drone>>) {
+        let dispatch = ongoing.iter_mut().filter(|v| v.severity < workload.severity && v.movable(workload.drone) > 0)
+            .filter(|v| !workload.excluded.contains(&v.source))
             .map(|v| (v.location.compute_distance(&workload.location), v))
             .min_by(|v1, v2| v1.0.partial_cmp(&v2.0).unwrap());
-        let drone = resources.iter_mut().filter(|v| v.power > 0)
+        let drone = resources.iter_mut().filter(|v| v.free(workload.drone) > 0 && !workload.excluded.contains(&v.uid))
             .map(|v| (v.location.compute_distance(&workload.location), v))
             .min_by(|v1, v2| v1.0.partial_cmp(&v2.0).unwrap());
         match (dispatch, drone) {
-            (Some(v), None) => (v.1.power, Some(Ok(v.1))),
-            (None, Some(v)) => (v.1.power, Some(Err(v.1))),
+            (Some(v), None) => (v.1.movable(workload.drone), Some(Ok(v.1))),
+            (None, Some(v)) => (v.1.free(workload.drone), Some(Err(v.1))),
             (Some(v1), Some(v2)) =>
-                if Self::assess_dispatch(v1.0, v2.0, (workload.severity - v1.1.severity) as i32) {
-                    (v1.1.power, Some(Ok(v1.1)))
+                if Self::assess_dispatch(v1.0, v2.0, (workload.severity - v1.1.severity) as i32, self.2) {
+                    debug!("preferring dispatch {} (severity {} < {}) at {:.1} over station {} at {:.1}",
+                           v1.1.id, v1.1.severity, workload.severity, v1.0, v2.1.uid, v2.0);
+                    (v1.1.movable(workload.drone), Some(Ok(v1.1)))
                 } else {
-                    (v2.1.power, Some(Err(v2.1)))
+                    debug!("preferring station {} at {:.1} over dispatch {} at {:.1}",
+                           v2.1.uid, v2.0, v1.1.id, v1.0);
+                    (v2.1.free(workload.drone), Some(Err(v2.1)))
                 }
             (None, None) =>
                 (0, None)
         }
     }
 
-    fn generate_route(&self, from: Coordinates, to: Coordinates) -> Vec<(f64, f64)> {
+    pub fn generate_route(&self, from: Coordinates, to: Coordinates) -> Vec<(f64, f64)> {
+        let _timer = ROUTE_COMPUTATION.start_timer();
         let start = self.0.iter().map(|v| (from.compute_distance(&v.location), v))
             .min_by(|v1, v2| v1.0.partial_cmp(&v2.0).unwrap())
             .unwrap();
@@ -276,7 +644,7 @@ impl Dispatcher {
             .min_by(|v1, v2| v1.0.partial_cmp(&v2.0).unwrap())
             .unwrap();
         if from.compute_distance(&to) <= start.0 + end.0 {
-            return vec![(from.x, from.y), (to.x, to.y)];
+            vec![(from.x, from.y), (to.x, to.y)]
         } else {
             let mut route = vec![(from.x, from.y)];
             route.extend(self.1[start.1.id][end.1.id].iter().map(|(p1, _)| {
@@ -288,17 +656,18 @@ impl Dispatcher {
         }
     }
 
-    pub fn online_dispatch_round(&self, mut workload: Workload, ongoing: &mut Vec<Dispatch>, resources: &mut Vec<Drone>, global_id: &AtomicUsize) -> (Vec<Mission>, Workload) {
-        let mut solution = Self::next_sat(&workload, ongoing, resources);
+    pub fn online_dispatch_round(&self, mut workload: Workload, ongoing: &mut [Dispatch], resources: &mut [Drone], global_id: &AtomicUsize) -> (Vec<Mission>, Workload) {
+        let mut solution = self.next_sat(&workload, ongoing, resources);
         let mut missions = vec![];
         while workload.consumption > 0 && solution.0 > 0 {
             if let Some(sol_to) = solution.1 {
                 match sol_to {
                     Ok(sol) => {
-                        let power = workload.consumption.min(sol.power);
+                        let (power, drones) = sol.take(workload.consumption, workload.drone);
                         missions.push(Mission {
                             id: global_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
                             power,
+                            drones,
                             severity: workload.severity,
                             from: sol.location,
                             to: workload.location,
@@ -307,13 +676,13 @@ impl Dispatcher {
                             source: sol.source.clone(),
                         });
                         workload.consumption -= power;
-                        sol.power -= power;
                     }
                     Err(sol) => {
-                        let power = workload.consumption.min(sol.power);
+                        let (power, drones) = sol.take(workload.consumption, workload.drone);
                         missions.push(Mission {
                             id: global_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
                             power,
+                            drones,
                             severity: workload.severity,
                             from: sol.location,
                             to: workload.location,
@@ -322,11 +691,10 @@ impl Dispatcher {
                             source: sol.uid.clone(),
                         });
                         workload.consumption -= power;
-                        sol.power -= power;
                     }
                 }
             }
-            solution = Self::next_sat(&workload, ongoing, resources);
+            solution = self.next_sat(&workload, ongoing, resources);
         }
         (missions, workload)
     }
@@ -1,28 +1,535 @@
 use crate::dispatch::*;
 use actix::{Actor, Handler, Message};
-use crate::database::{DatabaseAccess, DispatchedRoutes};
+use crate::database::{DatabaseAccess, Shift};
+use crate::shift;
+use crate::crew::ClockTime;
+use serde::Serialize;
 use std::sync::{Mutex, Arc};
 use std::sync::atomic::AtomicUsize;
 use actix::prelude::*;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, UNIX_EPOCH};
+use std::collections::HashMap;
+use uuid::Uuid;
+use log::{info, warn, error};
+use crate::events::{Event, EventHub};
+use crate::metrics::{DISPATCHER_MAILBOX, STATION_POWER, UNMET_DEMAND};
 
-pub struct DispatcherService(Arc<Mutex<DatabaseAccess>>, Arc<Mutex<Dispatcher>>, Vec<Drone>, Vec<Dispatch>, AtomicUsize, bool);
+// how often incidents short of units are dispatched again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct DispatcherService {
+    database: Arc<Mutex<DatabaseAccess>>,
+    dispatcher: Arc<Mutex<Dispatcher>>,
+    drones: Vec<Drone>,
+    dispatches: Vec<Dispatch>,
+    global_id: AtomicUsize,
+    available: bool,
+    // workloads still waiting for units, by incident
+    pending: HashMap<Uuid, Workload>,
+    // outbox entries of incidents whose dispatch is not stored yet
+    outbox: HashMap<Uuid, i64>,
+    // stations dispatchers excluded from an incident
+    excluded: HashMap<Uuid, Vec<String>>,
+    // station schedules, their times are `utc_offset` minutes east of UTC
+    shifts: Vec<Shift>,
+    // daily times crew and equipment with shifts of their own come or go
+    unit_shifts: Vec<ClockTime>,
+    utc_offset: i32,
+    // fires at the next shift boundary
+    shift_timer: Option<SpawnHandle>,
+    events: Addr<EventHub>,
+}
 
 impl DispatcherService {
-    pub fn new(db: Arc<Mutex<DatabaseAccess>>, dispatcher: Arc<Mutex<Dispatcher>>, available: bool) -> Self {
+    pub fn new(db: Arc<Mutex<DatabaseAccess>>, dispatcher: Arc<Mutex<Dispatcher>>, events: Addr<EventHub>, available: bool, utc_offset: i32) -> Self {
+        let (shifts, unit_shifts) = {
+            let database = db.lock().unwrap();
+            (database.find_shifts(None), database.find_unit_shift_times())
+        };
         let drone = if available {
-            db.lock().unwrap().find_police_station().iter().map(|ps| Drone {
-                power: ps.crew.len(),
-                location: Coordinates::from(ps.position),
-                uid: ps.id.clone(),
+            let database = db.lock().unwrap();
+            let on_duty = database.find_on_duty_units(shift::minute_of_day(crate::unix_timestamp(), utc_offset));
+            database.find_police_station().iter().map(|ps| {
+                let units = on_duty.iter().find(|(station, ..)| *station == ps.id)
+                    .map(|(_, crew, drones)| (*crew, *drones)).unwrap_or((0, 0));
+                let (power, drones) = shift::capacity(&ps.id, &shifts, units, crate::unix_timestamp(), utc_offset);
+                Drone {
+                    power,
+                    drones,
+                    location: Coordinates::from(ps.position),
+                    uid: ps.id.clone(),
+                }
             }).collect()
         } else {
             vec![]
         };
-        DispatcherService(db, dispatcher, drone, vec![], // use millisecond-timestamp for id marking
-                          AtomicUsize::new(std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as usize)
-                          , available)
+        DispatcherService {
+            database: db,
+            dispatcher,
+            drones: drone,
+            dispatches: vec![],
+            // use millisecond-timestamp for id marking
+            global_id: AtomicUsize::new(std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as usize),
+            available,
+            pending: HashMap::new(),
+            outbox: HashMap::new(),
+            excluded: HashMap::new(),
+            shifts,
+            unit_shifts,
+            utc_offset,
+            shift_timer: None,
+            events,
+        }
+    }
+
+    // picks up where the last run stopped: the saved state after a clean shutdown,
+    // otherwise every incident still on the map is dispatched afresh
+    fn restore(&mut self, ctx: &mut Context<Self>) {
+        let database = self.database.lock().unwrap();
+        let workloads = match database.take_dispatcher_state() {
+            Some((pending, dispatches)) => {
+                for dispatch in dispatches.iter() {
+                    if let Some(drone) = self.drones.iter_mut().find(|d| d.uid == dispatch.source) {
+                        drone.power = drone.power.saturating_sub(dispatch.power - dispatch.drones);
+                        drone.drones = drone.drones.saturating_sub(dispatch.drones);
+                    }
+                }
+                info!("restored {} active dispatch(es) and {} pending workload(s)", dispatches.len(), pending.len());
+                self.dispatches = dispatches;
+                pending
+            }
+            None => {
+                let marks = database.find_mark();
+                if !marks.is_empty() {
+                    warn!("no dispatcher state saved by the last run, dispatching {} incident(s) again", marks.len());
+                    if let Err(err) = database.clear_routes() {
+                        error!("unable to clear the routes of the last run: {}", err);
+                    }
+                }
+                // every open incident is dispatched again, including the ones still in the outbox;
+                // entries left pending are completed once their incident turns out to be known
+                if let Err(err) = database.complete_outbox(None) {
+                    error!("unable to complete the outbox entries: {}", err);
+                }
+                marks.iter().map(Workload::for_mark).collect()
+            }
+        };
+        for (incident, station) in database.find_exclusions() {
+            self.excluded.entry(incident).or_default().push(station);
+        }
+        let outbox = database.pending_outbox();
+        drop(database);
+        for workload in workloads {
+            submit(&ctx.address(), workload);
+        }
+        if !outbox.is_empty() {
+            info!("{} incident(s) in the outbox were not dispatched yet", outbox.len());
+        }
+        for (entry, incident) in outbox {
+            announce(&ctx.address(), entry, incident);
+        }
+    }
+
+    fn release(&mut self, assign_id: Uuid) {
+        let vec = &mut self.drones;
+        let database = self.database.lock().unwrap(); // lock for now
+        self.dispatches = self.dispatches.iter().cloned().filter_map(|v| {
+            if v.assign == assign_id {
+                for i in vec.iter_mut() {
+                    if v.source == i.uid {
+                        i.restore(&v) // assign dispatched power back
+                    }
+                    database.remove_routes(v.to_id).unwrap();
+                }
+                None
+            } else {
+                Some(v)
+            }
+        }).collect();
+        self.pending.remove(&assign_id);
+        if self.excluded.remove(&assign_id).is_some() {
+            database.clear_exclusions(assign_id).unwrap();
+        }
+        self.events.do_send(Event::RouteUpdated { incident: assign_id, routes: vec![] });
+        info!("incident {}: released its dispatches", assign_id);
+    }
+
+    // one dispatch round; its routes, the incident's status and its outbox entry are stored
+    // together, when that fails the units go back and the round is retried
+    fn dispatch(&mut self, msg: Workload) {
+        let msg = Workload { excluded: self.excluded.get(&msg.assign_id).cloned().unwrap_or_default(), ..msg };
+        let (drones, dispatches) = (self.drones.clone(), self.dispatches.clone());
+        let (missions, rest) = self.dispatcher.lock().unwrap()
+            .online_dispatch_round(msg.clone(), &mut self.dispatches, &mut self.drones, &self.global_id);
+        let outbox = self.outbox.get(&msg.assign_id).copied();
+        if !missions.is_empty() || outbox.is_some() {
+            let database = self.database.lock().unwrap();
+            let routes = missions.iter().map(|m| m.path_given.clone()).collect::<Vec<_>>();
+            let dispatched = match database.record_dispatch(msg.id, &routes, outbox) {
+                Ok(dispatched) => dispatched,
+                Err(err) => {
+                    error!("incident {}: unable to store its dispatch, retrying: {}", msg.id, err);
+                    self.drones = drones;
+                    self.dispatches = dispatches;
+                    self.pending.insert(msg.assign_id, msg);
+                    return;
+                }
+            };
+            self.outbox.remove(&msg.assign_id);
+            for mission in missions.iter() {
+                let reason = if mission.predecessor != 0 {
+                    format!("reassigned from lower severity dispatch {}", mission.predecessor)
+                } else {
+                    "nearest station with free units".to_string()
+                };
+                info!("incident {}: sending {} unit(s) from {} over {:.1} ({})",
+                      msg.id, mission.power, mission.source, mission.from.compute_distance(&mission.to), reason);
+                self.events.do_send(Event::MissionAssigned {
+                    incident: msg.id,
+                    station: mission.source.clone(),
+                    units: mission.power,
+                    reassigned_from: Some(mission.predecessor).filter(|p| *p != 0),
+                });
+                self.dispatches.push(Dispatch {
+                    id: self.global_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+                    power: mission.power,
+                    drones: mission.drones,
+                    severity: mission.severity,
+                    location: mission.to,
+                    source: mission.source.clone(),
+                    assign: msg.assign_id,
+                    to_id: msg.id,
+                })
+            }
+            if !missions.is_empty() {
+                self.events.do_send(Event::RouteUpdated { incident: msg.id, routes: database.find_routes(msg.id) });
+            }
+            if dispatched {
+                if let Some(incident) = database.find_mark_by_id(msg.id) {
+                    self.events.do_send(Event::IncidentUpdated { incident });
+                }
+            }
+            if !missions.is_empty() && rest.consumption > 0 {
+                warn!("incident {}: {} unit(s) still missing, waiting for units to free up", msg.id, rest.consumption);
+            }
+        }
+        if rest.consumption > 0 {
+            self.pending.insert(msg.assign_id, rest);
+        } else {
+            self.pending.remove(&msg.assign_id);
+        }
+    }
+
+    // brings the units of an edited incident in line with its new level and position
+    fn update(&mut self, workload: Workload) {
+        let id = workload.assign_id;
+        self.pending.remove(&id);
+        if !workload.drone {
+            // drones go home from incidents no longer taking them
+            for dispatch in self.dispatches.iter_mut().filter(|d| d.assign == id && d.drones > 0) {
+                let released = dispatch.release(dispatch.drones);
+                if let Some(drone) = self.drones.iter_mut().find(|s| s.uid == dispatch.source) {
+                    drone.restore(&released);
+                }
+            }
+            self.dispatches.retain(|d| d.assign != id || d.power > 0);
+        }
+        let assigned: usize = self.dispatches.iter().filter(|d| d.assign == id).map(|d| d.power).sum();
+        let mut surplus = assigned.saturating_sub(workload.consumption);
+        if surplus > 0 {
+            // the units farthest from the incident go home first
+            let drones = &mut self.drones;
+            let mut ours = self.dispatches.iter_mut().filter(|d| d.assign == id)
+                .map(|d| {
+                    let distance = drones.iter().find(|s| s.uid == d.source)
+                        .map(|s| s.location.compute_distance(&workload.location)).unwrap_or(f64::MAX);
+                    (distance, d)
+                })
+                .collect::<Vec<_>>();
+            ours.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+            for (_, dispatch) in ours {
+                let released = dispatch.release(surplus);
+                surplus -= released.power;
+                if let Some(drone) = drones.iter_mut().find(|s| s.uid == dispatch.source) {
+                    drone.restore(&released);
+                }
+                if surplus == 0 {
+                    break;
+                }
+            }
+            self.dispatches.retain(|d| d.assign != id || d.power > 0);
+        }
+        for dispatch in self.dispatches.iter_mut().filter(|d| d.assign == id) {
+            dispatch.severity = workload.severity;
+            dispatch.location = workload.location;
+        }
+        self.reroute(id);
+        info!("incident {}: updated to {} unit(s), {} assigned before", id, workload.consumption, assigned);
+        let missing = workload.consumption.saturating_sub(assigned);
+        if missing > 0 {
+            self.dispatch(Workload { consumption: missing, ..workload });
+        }
+    }
+
+    // the route of every unit on its way to the incident
+    fn routes_of(&self, id: Uuid) -> Vec<Vec<(f64, f64)>> {
+        let dispatcher = self.dispatcher.lock().unwrap();
+        self.dispatches.iter().filter(|d| d.assign == id)
+            .filter_map(|dispatch| self.drones.iter().find(|s| s.uid == dispatch.source)
+                .map(|drone| dispatcher.generate_route(drone.location, dispatch.location)))
+            .collect()
+    }
+
+    // recomputes the routes of every unit on its way to the incident
+    fn reroute(&mut self, id: Uuid) {
+        let routes = self.routes_of(id);
+        let database = self.database.lock().unwrap();
+        if let Err(err) = database.replace_routes(id, &routes) {
+            error!("incident {}: unable to store its new routes: {}", id, err);
+        }
+        self.events.do_send(Event::RouteUpdated { incident: id, routes: database.find_routes(id) });
+    }
+
+    // sends the units of the given stations on their way to an incident back home,
+    // returning how many there were
+    fn recall(&mut self, id: Uuid, stations: &[String]) -> usize {
+        let mut recalled = 0;
+        for dispatch in self.dispatches.iter().filter(|d| d.assign == id && stations.contains(&d.source)) {
+            if let Some(drone) = self.drones.iter_mut().find(|s| s.uid == dispatch.source) {
+                drone.restore(dispatch);
+            }
+            recalled += dispatch.power;
+        }
+        self.dispatches.retain(|d| d.assign != id || !stations.contains(&d.source));
+        recalled
+    }
+
+    // applies a dispatcher's override and stores it with the incident's new routes; units
+    // recalled from the incident are looked for elsewhere
+    fn manual_dispatch(&mut self, msg: ManualDispatch) -> Result<Overridden, Rejected> {
+        let workload = msg.workload;
+        let id = workload.assign_id;
+        let stations = match &msg.change {
+            Override::Assign { station, .. } | Override::Cancel { station } => vec![station.clone()],
+            Override::Exclude { stations } => stations.clone(),
+        };
+        if let Some(unknown) = stations.iter().find(|station| !self.drones.iter().any(|d| &d.uid == *station)) {
+            return Err(Rejected::UnknownStation(unknown.clone()));
+        }
+        let before = self.dispatch_result(id);
+        let snapshot = (self.drones.clone(), self.dispatches.clone(), self.pending.get(&id).cloned(), self.excluded.get(&id).cloned());
+        let mut excluded = None;
+        let mut assigned = None;
+        let mut recalled = 0;
+        match msg.change {
+            Override::Assign { station, units } => {
+                if self.excluded.get(&id).is_some_and(|e| e.contains(&station)) {
+                    return Err(Rejected::StationExcluded);
+                }
+                let drone = self.drones.iter_mut().find(|d| d.uid == station).unwrap();
+                let (units, drones) = drone.take(units, workload.drone);
+                if units == 0 {
+                    return Err(Rejected::NoUnits);
+                }
+                self.dispatches.push(Dispatch {
+                    id: self.global_id.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+                    power: units,
+                    drones,
+                    severity: workload.severity,
+                    location: workload.location,
+                    assign: id,
+                    source: station.clone(),
+                    to_id: workload.id,
+                });
+                if let Some(pending) = self.pending.get_mut(&id) {
+                    pending.consumption = pending.consumption.saturating_sub(units);
+                    if pending.consumption == 0 {
+                        self.pending.remove(&id);
+                    }
+                }
+                assigned = Some((station, units));
+            }
+            Override::Cancel { station } => {
+                recalled = self.recall(id, &stations);
+                if recalled == 0 {
+                    return Err(Rejected::NotAssigned);
+                }
+                let mut stations = self.excluded.get(&id).cloned().unwrap_or_default();
+                if !stations.contains(&station) {
+                    stations.push(station);
+                }
+                excluded = Some(stations);
+            }
+            Override::Exclude { mut stations } => {
+                recalled = self.recall(id, &stations);
+                stations.sort();
+                stations.dedup();
+                excluded = Some(stations);
+            }
+        }
+        match &excluded {
+            Some(stations) if stations.is_empty() => { self.excluded.remove(&id); }
+            Some(stations) => { self.excluded.insert(id, stations.clone()); }
+            None => {}
+        }
+        if recalled > 0 {
+            self.pending.entry(id)
+                .or_insert_with(|| Workload { consumption: 0, ..workload.clone() })
+                .consumption += recalled;
+        }
+        let routes = self.routes_of(id);
+        let stored = self.database.lock().unwrap().override_dispatch(id, &routes, excluded.as_deref(), &msg.actor);
+        let dispatched = match stored {
+            Ok(dispatched) => dispatched,
+            Err(err) => {
+                error!("incident {}: unable to store the override by {}: {}", id, msg.actor, err);
+                let (drones, dispatches, pending, excluded) = snapshot;
+                self.drones = drones;
+                self.dispatches = dispatches;
+                match pending {
+                    Some(pending) => { self.pending.insert(id, pending); }
+                    None => { self.pending.remove(&id); }
+                }
+                match excluded {
+                    Some(excluded) => { self.excluded.insert(id, excluded); }
+                    None => { self.excluded.remove(&id); }
+                }
+                return Err(Rejected::Failed);
+            }
+        };
+        if let Some((station, units)) = assigned {
+            info!("incident {}: {} sent {} unit(s) from {}", id, msg.actor, units, station);
+            self.events.do_send(Event::MissionAssigned { incident: id, station, units, reassigned_from: None });
+        }
+        if recalled > 0 {
+            info!("incident {}: {} recalled {} unit(s), excluding {:?}", id, msg.actor, recalled, self.excluded.get(&id));
+        }
+        self.events.do_send(Event::RouteUpdated { incident: id, routes: self.database.lock().unwrap().find_routes(id) });
+        if dispatched {
+            if let Some(incident) = self.database.lock().unwrap().find_mark_by_id(id) {
+                self.events.do_send(Event::IncidentUpdated { incident });
+            }
+        }
+        if let Some(pending) = self.pending.get(&id).cloned().filter(|_| recalled > 0) {
+            self.dispatch(pending);
+        }
+        Ok(Overridden { before, after: self.dispatch_result(id) })
     }
+
+    // recounts the units of a station from its current shift and its on duty crew and drones;
+    // units already sent stay with their incidents
+    fn refresh_station(&mut self, id: &str) {
+        let (station, on_duty, drones) = {
+            let database = self.database.lock().unwrap();
+            let station = database.find_police_station().into_iter().find(|ps| ps.id == id);
+            let units = database.find_on_duty_units(shift::minute_of_day(crate::unix_timestamp(), self.utc_offset)).into_iter().find(|(station, ..)| station == id)
+                .map(|(_, crew, drones)| (crew, drones)).unwrap_or((0, 0));
+            let (crew, drones) = shift::capacity(id, &self.shifts, units, crate::unix_timestamp(), self.utc_offset);
+            (station, crew, drones)
+        };
+        let sent = self.dispatches.iter().filter(|d| d.source == id).map(|d| d.drones).sum::<usize>();
+        let in_use = self.dispatches.iter().filter(|d| d.source == id).map(|d| d.power).sum::<usize>() - sent;
+        match station {
+            Some(ps) => {
+                let drone = Drone {
+                    power: on_duty.saturating_sub(in_use),
+                    drones: drones.saturating_sub(sent),
+                    location: Coordinates::from(ps.position),
+                    uid: ps.id,
+                };
+                let moved = match self.drones.iter_mut().find(|d| d.uid == id) {
+                    Some(existing) => {
+                        let moved = existing.location.compute_distance(&drone.location) > 0.0;
+                        *existing = drone;
+                        moved
+                    }
+                    None => {
+                        self.drones.push(drone);
+                        false
+                    }
+                };
+                info!("station {}: {} unit(s) and {} drone(s) on duty, {} and {} sent", id, on_duty, drones, in_use, sent);
+                if moved {
+                    // units on their way now leave from the new position
+                    let mut incidents = self.dispatches.iter().filter(|d| d.source == id).map(|d| d.assign).collect::<Vec<_>>();
+                    incidents.sort_unstable();
+                    incidents.dedup();
+                    incidents.into_iter().for_each(|incident| self.reroute(incident));
+                }
+            }
+            None => self.drones.retain(|d| d.uid != id),
+        }
+    }
+
+    // sets up the timer recounting every station at the next shift start or end
+    fn schedule_shifts(&mut self, ctx: &mut Context<Self>) {
+        if let Some(timer) = self.shift_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        let now = crate::unix_timestamp();
+        let station_shift = shift::next_boundary(&self.shifts, now, self.utc_offset);
+        let unit_shift = shift::next_daily_boundary(&self.unit_shifts, now, self.utc_offset);
+        if let Some(wait) = station_shift.into_iter().chain(unit_shift).min() {
+            // a second late, to be well inside the next shift
+            self.shift_timer = Some(ctx.run_later(Duration::from_secs(wait as u64 + 1), |act, ctx| {
+                act.shift_timer = None;
+                if act.available {
+                    info!("shift change, recounting the units of every station");
+                    act.tracking_units(|act| {
+                        let stations = act.drones.iter().map(|d| d.uid.clone()).collect::<Vec<_>>();
+                        stations.iter().for_each(|station| act.refresh_station(station));
+                        act.retry_pending();
+                    });
+                    act.schedule_shifts(ctx);
+                }
+            }));
+        }
+    }
+
+    // what has been sent to an incident so far
+    fn dispatch_result(&self, incident: Uuid) -> DispatchResult {
+        DispatchResult {
+            incident,
+            missions: self.dispatches.iter().filter(|d| d.assign == incident)
+                .map(|d| AssignedUnits { station: d.source.clone(), units: d.power }).collect(),
+            routes: self.database.lock().unwrap().find_routes(incident),
+            missing: self.pending.get(&incident).map(|w| w.consumption).unwrap_or(0),
+        }
+    }
+
+    // gives every incident still short of units another dispatch round
+    fn retry_pending(&mut self) {
+        let pending = self.pending.values().cloned().collect::<Vec<_>>();
+        for workload in pending {
+            self.dispatch(workload);
+        }
+    }
+
+    // applies a change and reports every station whose available units it changed
+    fn tracking_units<F: FnOnce(&mut Self)>(&mut self, change: F) {
+        let before = self.drones.iter().map(|d| d.free(true)).collect::<Vec<_>>();
+        change(self);
+        for (drone, before) in self.drones.iter().zip(before) {
+            if drone.free(true) != before {
+                self.events.do_send(Event::UnitStatusChanged { station: drone.uid.clone(), available: drone.free(true) });
+            }
+        }
+        self.publish_metrics();
+    }
+
+    // unmet demand (per incident) and available power (per station) as seen by /metrics
+    fn publish_metrics(&self) {
+        UNMET_DEMAND.set(self.pending.values().map(|w| w.consumption).sum::<usize>() as i64);
+        for drone in self.drones.iter() {
+            STATION_POWER.with_label_values(&[&drone.uid]).set(drone.free(true) as i64);
+        }
+    }
+}
+
+/// Queues a workload for the dispatcher, keeping the mailbox depth gauge in step.
+pub fn submit(dispatcher: &Addr<DispatcherService>, workload: Workload) {
+    DISPATCHER_MAILBOX.inc();
+    dispatcher.do_send(workload);
 }
 
 impl Message for Workload {
@@ -31,59 +538,299 @@ impl Message for Workload {
 
 impl Actor for DispatcherService {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.available {
+            self.restore(ctx);
+            self.schedule_shifts(ctx);
+            ctx.run_interval(RETRY_INTERVAL, |act, _| {
+                if act.available && !act.pending.is_empty() {
+                    act.tracking_units(Self::retry_pending);
+                }
+            });
+        }
+        self.publish_metrics();
+    }
 }
 
-impl Handler<Workload> for DispatcherService {
-    type Result = Result<(), ()>;
+/// A new incident, committed together with outbox entry `entry`.
+pub struct DispatchIncident {
+    pub entry: i64,
+    pub incident: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct AssignedUnits {
+    station: String,
+    units: usize,
+}
+
+/// The units on their way to an incident, their routes and how many are still missing.
+#[derive(Serialize)]
+pub struct DispatchResult {
+    incident: Uuid,
+    missions: Vec<AssignedUnits>,
+    routes: Vec<Vec<(f64, f64)>>,
+    missing: usize,
+}
 
-    fn handle(&mut self, msg: Workload, ctx: &mut Self::Context) -> Self::Result {
-        if !self.5 {
+impl Message for DispatchIncident {
+    type Result = Result<DispatchResult, ()>;
+}
+
+/// Hands a new incident to the dispatcher.
+pub fn announce(dispatcher: &Addr<DispatcherService>, entry: i64, incident: Uuid) {
+    DISPATCHER_MAILBOX.inc();
+    dispatcher.do_send(DispatchIncident { entry, incident });
+}
+
+/// Like `announce`, resolving to the units sent once the incident is dispatched.
+pub fn announce_waiting(dispatcher: &Addr<DispatcherService>, entry: i64, incident: Uuid) -> Request<DispatcherService, DispatchIncident> {
+    DISPATCHER_MAILBOX.inc();
+    dispatcher.send(DispatchIncident { entry, incident })
+}
+
+impl Handler<DispatchIncident> for DispatcherService {
+    type Result = Result<DispatchResult, ()>;
+
+    fn handle(&mut self, msg: DispatchIncident, _: &mut Self::Context) -> Self::Result {
+        DISPATCHER_MAILBOX.dec();
+        if !self.available {
             return Err(());
         }
-        let dispatcher = self.1.lock().unwrap();
-
-        if msg.is_remove {
-            let vec = &mut self.2;
-            let database = self.0.lock().unwrap(); // lock for now
-            self.3 = self.3.iter().cloned().filter_map(|v| {
-                if v.assign == msg.assign_id {
-                    for i in vec.iter_mut() {
-                        if v.source == i.uid {
-                            i.power += v.power // assign dispatched power back
+        let workload = {
+            let database = self.database.lock().unwrap();
+            // entries are processed once, a restart may announce them again
+            if !database.outbox_pending(msg.entry) {
+                None
+            } else {
+                let known = self.pending.contains_key(&msg.incident) || self.dispatches.iter().any(|d| d.assign == msg.incident);
+                match database.find_mark_by_id(msg.incident).filter(|mark| !mark.status.is_closed() && !known) {
+                    Some(mark) => Some(Workload::for_mark(&mark)),
+                    None => {
+                        if let Err(err) = database.complete_outbox(Some(msg.entry)) {
+                            error!("unable to complete outbox entry {}: {}", msg.entry, err);
                         }
-                        database.remove_routes(v.to_id).unwrap();
+                        None
                     }
-                    None
-                } else {
-                    Some(v)
                 }
-            }).collect();
+            }
+        };
+        if let Some(workload) = workload {
+            self.outbox.insert(msg.incident, msg.entry);
+            self.tracking_units(|act| act.dispatch(workload));
+        }
+        Ok(self.dispatch_result(msg.incident))
+    }
+}
+
+/// An incident was edited, `0` carries its new level, position and drone flag.
+pub struct IncidentChanged(pub Workload);
+
+impl Message for IncidentChanged {
+    type Result = Result<(), ()>;
+}
+
+/// Hands an edited incident to the dispatcher.
+pub fn change(dispatcher: &Addr<DispatcherService>, workload: Workload) {
+    DISPATCHER_MAILBOX.inc();
+    dispatcher.do_send(IncidentChanged(workload));
+}
+
+impl Handler<IncidentChanged> for DispatcherService {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: IncidentChanged, _: &mut Self::Context) -> Self::Result {
+        DISPATCHER_MAILBOX.dec();
+        if !self.available {
+            return Err(());
+        }
+        self.tracking_units(|act| act.update(msg.0));
+        Ok(())
+    }
+}
+
+/// A dispatcher's change to the units the engine chose for an incident.
+pub enum Override {
+    /// send up to `units` free units of `station`
+    Assign { station: String, units: usize },
+    /// recall the units of `station` and keep the incident away from it
+    Cancel { station: String },
+    /// replace the stations the incident is kept away from, recalling their units
+    Exclude { stations: Vec<String> },
+}
+
+/// `change` to incident `workload`, made by `actor`.
+pub struct ManualDispatch {
+    pub workload: Workload,
+    pub change: Override,
+    pub actor: String,
+}
+
+/// What had been sent to the incident before the override, and after it.
+pub struct Overridden {
+    pub before: DispatchResult,
+    pub after: DispatchResult,
+}
+
+pub enum Rejected {
+    Unavailable,
+    UnknownStation(String),
+    StationExcluded,
+    NoUnits,
+    NotAssigned,
+    Failed,
+}
+
+impl Message for ManualDispatch {
+    type Result = Result<Overridden, Rejected>;
+}
+
+impl Handler<ManualDispatch> for DispatcherService {
+    type Result = Result<Overridden, Rejected>;
+
+    fn handle(&mut self, msg: ManualDispatch, _: &mut Self::Context) -> Self::Result {
+        if !self.available {
+            return Err(Rejected::Unavailable);
+        }
+        let mut outcome = Err(Rejected::Unavailable);
+        self.tracking_units(|act| outcome = act.manual_dispatch(msg));
+        outcome
+    }
+}
+
+/// The crew, schedule, position or existence of station `0` changed.
+pub struct StationChanged(pub String);
+
+impl Message for StationChanged {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<StationChanged> for DispatcherService {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: StationChanged, ctx: &mut Self::Context) -> Self::Result {
+        if !self.available {
+            return Err(());
+        }
+        {
+            let database = self.database.lock().unwrap();
+            self.shifts = database.find_shifts(None);
+            self.unit_shifts = database.find_unit_shift_times();
+        }
+        self.schedule_shifts(ctx);
+        self.tracking_units(|act| {
+            act.refresh_station(&msg.0);
+            // more crew may cover incidents still waiting
+            act.retry_pending();
+        });
+        Ok(())
+    }
+}
+
+/// What-if dispatch of `0` against copies of the current stations and dispatches;
+/// nothing is assigned, stored or announced.
+pub struct Preview(pub Workload);
+
+/// The missions a dispatch round would start and the units it could not find.
+pub struct Proposal {
+    pub missions: Vec<Mission>,
+    pub missing: usize,
+}
+
+impl Message for Preview {
+    type Result = Result<Proposal, ()>;
+}
+
+impl Handler<Preview> for DispatcherService {
+    type Result = Result<Proposal, ()>;
+
+    fn handle(&mut self, msg: Preview, _: &mut Self::Context) -> Self::Result {
+        if !self.available {
+            return Err(());
+        }
+        let (mut drones, mut dispatches) = (self.drones.clone(), self.dispatches.clone());
+        // mission ids are thrown away with the copies, keep the real sequence untouched
+        let (missions, rest) = self.dispatcher.lock().unwrap()
+            .online_dispatch_round(msg.0, &mut dispatches, &mut drones, &AtomicUsize::new(0));
+        Ok(Proposal { missions, missing: rest.consumption })
+    }
+}
+
+/// Readiness probe, answered with the dispatcher's view of its own state.
+pub struct Ping;
+
+pub struct Pong {
+    pub available: bool,
+    pub intersections: usize,
+    pub stations: usize,
+}
+
+impl Message for Ping {
+    type Result = Result<Pong, ()>;
+}
+
+impl Handler<Ping> for DispatcherService {
+    type Result = Result<Pong, ()>;
+
+    fn handle(&mut self, _: Ping, _: &mut Self::Context) -> Self::Result {
+        Ok(Pong {
+            available: self.available,
+            intersections: self.dispatcher.lock().unwrap().topology_size(),
+            stations: self.drones.len(),
+        })
+    }
+}
+
+/// Sent once the http servers stopped: everything queued before it has been
+/// dispatched, so the pending workloads and active dispatches are saved for the next start.
+pub struct Shutdown;
+
+impl Message for Shutdown {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<Shutdown> for DispatcherService {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Self::Context) -> Self::Result {
+        if !self.available {
             return Ok(());
         }
-        let dispatched =
-            dispatcher.online_dispatch_round(msg.clone(), &mut self.3, &mut self.2, &self.4);
-        if dispatched.1.consumption < msg.consumption { // or else there's no need to lock the database
-            let database = self.0.lock().unwrap(); // lock for now
-            for mission in dispatched.0.iter() {
-                database.add_route(DispatchedRoutes {
-                    route: mission.path_given.clone(),
-                    belong: msg.id,
-                });
-                self.3.push(Dispatch {
-                    id: self.4.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
-                    power: mission.power,
-                    severity: mission.severity,
-                    location: mission.to,
-                    source: mission.source.clone(),
-                    assign: msg.assign_id,
-                    to_id: msg.id,
-                })
+        self.available = false;
+        let pending = self.pending.values().cloned().collect::<Vec<_>>();
+        let saved = self.database.lock().unwrap().save_dispatcher_state(&pending, &self.dispatches);
+        ctx.stop();
+        match saved {
+            Ok(()) => {
+                info!("saved {} active dispatch(es) and {} pending workload(s)", self.dispatches.len(), pending.len());
+                Ok(())
+            }
+            Err(err) => {
+                error!("unable to save the dispatcher state: {}", err);
+                Err(())
             }
-            drop(database); // drop the reference for now
         }
-        if dispatched.1.consumption > 0 {
-            ctx.address().do_send(dispatched.1);
+    }
+}
+
+impl Handler<Workload> for DispatcherService {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: Workload, _: &mut Self::Context) -> Self::Result {
+        DISPATCHER_MAILBOX.dec();
+        if !self.available {
+            return Err(());
         }
+        self.tracking_units(|act| {
+            if msg.is_remove {
+                act.release(msg.assign_id);
+                // freed units may cover incidents still waiting
+                act.retry_pending();
+            } else {
+                act.dispatch(msg);
+            }
+        });
         Ok(())
     }
 }
@@ -0,0 +1,197 @@
+use actix::prelude::*;
+use actix_web::{error, HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Bytes, Data, Query};
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use crate::database::{DatabaseAccess, OperatorMark};
+use crate::result;
+
+// events kept for subscribers resuming with `since` / `Last-Event-ID`
+const HISTORY: usize = 1024;
+
+// keeps idle connections open and drops subscribers whose session ended
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Everything pushed to `/events` subscribers, tagged by `type`.
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    IncidentCreated { incident: OperatorMark },
+    IncidentUpdated { incident: OperatorMark },
+    IncidentDeleted { id: Uuid },
+    MissionAssigned { incident: Uuid, station: String, units: usize, reassigned_from: Option<usize> },
+    RouteUpdated { incident: Uuid, routes: Vec<Vec<(f64, f64)>> },
+    UnitStatusChanged { station: String, available: usize },
+}
+
+impl Event {
+    // the `type` tag, also used as the SSE event name
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::IncidentCreated { .. } => "incident_created",
+            Event::IncidentUpdated { .. } => "incident_updated",
+            Event::IncidentDeleted { .. } => "incident_deleted",
+            Event::MissionAssigned { .. } => "mission_assigned",
+            Event::RouteUpdated { .. } => "route_updated",
+            Event::UnitStatusChanged { .. } => "unit_status_changed",
+        }
+    }
+}
+
+impl Message for Event {
+    type Result = ();
+}
+
+struct Subscriber {
+    token: String,
+    sender: UnboundedSender<Bytes>,
+}
+
+/// Numbers every event, keeps the most recent ones for resuming
+/// and fans them out to the subscribed event streams.
+pub struct EventHub {
+    database: Arc<Mutex<DatabaseAccess>>,
+    // start of this process in milliseconds, event ids are `<run>-<sequence>` so that
+    // ids handed out before a restart are told apart from the new ones
+    run: u64,
+    sequence: u64,
+    history: VecDeque<(u64, Bytes)>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventHub {
+    pub fn new(database: Arc<Mutex<DatabaseAccess>>) -> Self {
+        EventHub {
+            database,
+            run: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            sequence: 0,
+            history: VecDeque::with_capacity(HISTORY),
+            subscribers: vec![],
+        }
+    }
+
+    // tells a subscriber its position is unknown or too old, so it has to reload everything
+    fn reset_frame(&self) -> Bytes {
+        Bytes::from(format!("id: {}-{}\nevent: reset\ndata: {{\"sequence\": {}}}\n\n", self.run, self.sequence, self.sequence))
+    }
+}
+
+fn frame(run: u64, sequence: u64, event: &Event) -> Bytes {
+    let data = serde_json::to_string(event).unwrap();
+    Bytes::from(format!("id: {}-{}\nevent: {}\ndata: {}\n\n", run, sequence, event.kind(), data))
+}
+
+// the frames following event `since` of run `run`, none when it was handed out by another
+// run (before a restart), lies ahead or fell out of the history
+fn resume(run: u64, sequence: u64, history: &VecDeque<(u64, Bytes)>, since: &str) -> Option<Vec<Bytes>> {
+    let (since_run, since) = since.split_once('-')?;
+    let (since_run, since) = (since_run.parse::<u64>().ok()?, since.parse::<u64>().ok()?);
+    let oldest = history.front().map(|(sequence, _)| *sequence).unwrap_or(sequence + 1);
+    if since_run != run || since > sequence || since + 1 < oldest {
+        return None;
+    }
+    Some(history.iter().filter(|(sequence, _)| *sequence > since).map(|(_, frame)| frame.clone()).collect())
+}
+
+impl Actor for EventHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, _ctx| {
+            let database = act.database.lock().unwrap();
+            act.subscribers.retain(|s| database.session_active(&s.token)
+                && s.sender.unbounded_send(Bytes::from_static(b": heartbeat\n\n")).is_ok());
+        });
+    }
+}
+
+impl Handler<Event> for EventHub {
+    type Result = ();
+
+    fn handle(&mut self, event: Event, _: &mut Self::Context) {
+        self.sequence += 1;
+        let frame = frame(self.run, self.sequence, &event);
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.sequence, frame.clone()));
+        self.subscribers.retain(|s| s.sender.unbounded_send(frame.clone()).is_ok());
+    }
+}
+
+struct Subscribe {
+    token: String,
+    since: Option<String>,
+    sender: UnboundedSender<Bytes>,
+}
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+impl Handler<Subscribe> for EventHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) {
+        if let Some(since) = msg.since {
+            let frames = resume(self.run, self.sequence, &self.history, &since).unwrap_or_else(|| vec![self.reset_frame()]);
+            for frame in frames {
+                if msg.sender.unbounded_send(frame).is_err() {
+                    return;
+                }
+            }
+        }
+        self.subscribers.push(Subscriber { token: msg.token, sender: msg.sender });
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeQuery {
+    since: Option<String>,
+}
+
+/// `/events`: server-sent events for logged in users. A reconnecting `EventSource`
+/// resumes after its `Last-Event-ID`, other clients may pass the last id as `?since=<id>`.
+pub fn subscribe(database: Data<Arc<Mutex<DatabaseAccess>>>, hub: Data<Addr<EventHub>>, query: Query<SubscribeQuery>, request: HttpRequest) -> impl Responder {
+    let since = request.headers().get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| query.since.clone());
+    let info = crate::login::get_login(database, request);
+    if let Some(i) = info {
+        let (sender, receiver) = unbounded();
+        hub.do_send(Subscribe { token: i.token, since, sender });
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .streaming(receiver.map_err(|_| error::ErrorInternalServerError("event stream closed")));
+    }
+    result(&mut HttpResponse::Unauthorized(), "failed")
+}
+
+#[test]
+fn test_event_frame() {
+    let event = Event::UnitStatusChanged { station: "ps1".to_string(), available: 2 };
+    assert_eq!(&frame(3, 7, &event)[..],
+               &b"id: 3-7\nevent: unit_status_changed\ndata: {\"type\":\"unit_status_changed\",\"station\":\"ps1\",\"available\":2}\n\n"[..]);
+}
+
+#[test]
+fn test_resume_after_restart() {
+    let event = Event::UnitStatusChanged { station: "ps1".to_string(), available: 2 };
+    // the new run has sent 10 events, the client last saw event 5 of the run before
+    let history = (1..=10).map(|sequence| (sequence, frame(2, sequence, &event))).collect::<VecDeque<_>>();
+    assert!(resume(2, 10, &history, "1-5").is_none());
+    assert_eq!(resume(2, 10, &history, "2-5").unwrap(), history.iter().skip(5).map(|(_, frame)| frame.clone()).collect::<Vec<_>>());
+    assert!(resume(2, 10, &history, "2-10").unwrap().is_empty());
+    assert!(resume(2, 10, &history, "2-11").is_none());
+    assert!(resume(2, 10, &history, "5").is_none());
+    let trimmed = history.iter().skip(3).cloned().collect::<VecDeque<_>>();
+    assert!(resume(2, 10, &trimmed, "2-2").is_none());
+    assert_eq!(resume(2, 10, &trimmed, "2-3").unwrap().len(), 7);
+}
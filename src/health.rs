@@ -0,0 +1,91 @@
+use actix::Addr;
+use actix_web::{Error, HttpResponse, Responder};
+use actix_web::web::Data;
+use futures::Future;
+use serde::Serialize;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::{Duration, Instant};
+use crate::database::DatabaseAccess;
+use crate::dispatcher::{DispatcherService, Ping};
+
+// how long the dispatcher actor may take to answer before it counts as stuck
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+// how long the probe waits for the shared database connection before reporting it busy
+const LOCK_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(ok: bool, detail: String) -> Self {
+        Check { ok, detail }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    database: Check,
+    topology: Check,
+    dispatcher: Check,
+}
+
+// pings the database unless its connection stays held by someone else, so a stuck query
+// fails the probe instead of queueing it (and every request after it) behind the lock
+fn check_database(database: &Mutex<DatabaseAccess>) -> Check {
+    let deadline = Instant::now() + LOCK_WAIT;
+    loop {
+        match database.try_lock() {
+            Ok(database) => return if database.ping() {
+                Check::new(true, "reachable".to_string())
+            } else {
+                Check::new(false, "query failed".to_string())
+            },
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Err(TryLockError::WouldBlock) => return Check::new(false, format!("connection busy for over {}ms", LOCK_WAIT.as_millis())),
+            Err(TryLockError::Poisoned(_)) => return Check::new(false, "connection lost by a failed request".to_string()),
+        }
+    }
+}
+
+/// `/healthz`: the process is up and serving requests.
+pub fn alive() -> impl Responder {
+    HttpResponse::Ok().content_type("application/json").body("{\"status\": \"alive\"}")
+}
+
+/// `/readyz`: the database answers, the road topology is loaded and the
+/// dispatcher actor is processing its mailbox.
+pub fn ready(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>) -> impl Future<Item=HttpResponse, Error=Error> {
+    let database = check_database(&database);
+    dispatcher.send(Ping).timeout(PING_TIMEOUT).then(move |pong| {
+        let (topology, dispatcher) = match pong {
+            Ok(Ok(pong)) => (
+                if pong.available {
+                    Check::new(true, format!("{} intersections, {} stations", pong.intersections, pong.stations))
+                } else {
+                    Check::new(false, "not initialized, upload the map data and restart".to_string())
+                },
+                Check::new(true, "responsive".to_string())
+            ),
+            _ => (
+                Check::new(false, "unknown".to_string()),
+                Check::new(false, format!("no answer within {}s", PING_TIMEOUT.as_secs()))
+            )
+        };
+        let ready = database.ok && topology.ok && dispatcher.ok;
+        let body = Readiness {
+            status: if ready { "ready" } else { "not ready" },
+            database,
+            topology,
+            dispatcher,
+        };
+        Ok(if ready {
+            HttpResponse::Ok().json(body)
+        } else {
+            HttpResponse::ServiceUnavailable().json(body)
+        })
+    })
+}
@@ -0,0 +1,153 @@
+use actix::Addr;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Data, Json};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use crate::database::{DatabaseAccess, OperatorMark, Transition};
+use crate::dispatch::Workload;
+use crate::dispatcher::{self, DispatcherService};
+use crate::events::{Event, EventHub};
+use crate::audit::{self, Auditor};
+use crate::result;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentStatus {
+    #[default]
+    Reported,
+    Dispatched,
+    OnScene,
+    Resolved,
+    Cancelled,
+    FalseAlarm,
+}
+
+/// Reason codes accepted when an incident is closed.
+pub const CLOSURE_REASONS: [&str; 7] = ["handled", "referred", "duplicate", "withdrawn", "no_trace", "hoax", "other"];
+
+// statuses an incident is shown on the map and dispatched for
+pub const OPEN_STATUSES: [&str; 3] = ["reported", "dispatched", "on_scene"];
+
+impl IncidentStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IncidentStatus::Reported => "reported",
+            IncidentStatus::Dispatched => "dispatched",
+            IncidentStatus::OnScene => "on_scene",
+            IncidentStatus::Resolved => "resolved",
+            IncidentStatus::Cancelled => "cancelled",
+            IncidentStatus::FalseAlarm => "false_alarm",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        [IncidentStatus::Reported, IncidentStatus::Dispatched, IncidentStatus::OnScene,
+            IncidentStatus::Resolved, IncidentStatus::Cancelled, IncidentStatus::FalseAlarm]
+            .iter().cloned().find(|s| s.as_str() == value)
+    }
+
+    pub fn is_closed(self) -> bool {
+        !OPEN_STATUSES.contains(&self.as_str())
+    }
+
+    pub fn can_become(self, next: IncidentStatus) -> bool {
+        use IncidentStatus::*;
+        match self {
+            Reported => [Dispatched, Resolved, Cancelled, FalseAlarm].contains(&next),
+            Dispatched => [OnScene, Resolved, Cancelled, FalseAlarm].contains(&next),
+            OnScene => [Resolved, Cancelled, FalseAlarm].contains(&next),
+            Resolved | Cancelled | FalseAlarm => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangeStatusInfo {
+    uid: Uuid,
+    status: IncidentStatus,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryInfo {
+    uid: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct History {
+    incident: OperatorMark,
+    transitions: Vec<Transition>,
+}
+
+/// Moves an incident along its workflow. Closing it needs one of the
+/// `CLOSURE_REASONS` and sends its units back; the record itself is kept.
+pub fn close_or_advance(database: &DatabaseAccess, dispatcher: &Addr<DispatcherService>, events: &Addr<EventHub>,
+                        uid: Uuid, next: IncidentStatus, reason: Option<&str>, actor: &Auditor) -> HttpResponse {
+    let mark = match database.find_mark_by_id(uid) {
+        Some(mark) => mark,
+        None => return result(&mut HttpResponse::NotFound(), "Mark not found !")
+    };
+    if !mark.status.can_become(next) {
+        return result(&mut HttpResponse::Conflict(),
+                      &format!("Cannot change status from {} to {} !", mark.status.as_str(), next.as_str()));
+    }
+    if next.is_closed() && !reason.is_some_and(|r| CLOSURE_REASONS.contains(&r)) {
+        return result(&mut HttpResponse::BadRequest(),
+                      &format!("Closing needs a reason: {} !", CLOSURE_REASONS.join(", ")));
+    }
+    if !database.transition_mark(uid, mark.status, next, reason, actor.username()) {
+        // somebody else moved it first
+        return result(&mut HttpResponse::Conflict(), "Status changed concurrently, reload and retry !");
+    }
+    let after = OperatorMark { status: next, ..mark.clone() };
+    actor.record(database, "change_status", "mark", &uid.to_string(), Some(&mark), Some(&after));
+    events.do_send(Event::IncidentUpdated { incident: after });
+    if next.is_closed() {
+        dispatcher::submit(dispatcher, Workload::delete(uid));
+        events.do_send(Event::IncidentDeleted { id: uid });
+    }
+    result(&mut HttpResponse::Ok(), "success")
+}
+
+pub fn change_status(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<ChangeStatusInfo>, dispatcher: Data<Addr<DispatcherService>>, events: Data<Addr<EventHub>>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 0 || i.user_type == 2 {
+            return close_or_advance(&database.lock().unwrap(), &dispatcher, &events,
+                                    login.uid, login.status, login.reason.as_deref(), &Auditor::new(&i, ip));
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+/// An incident (open or closed) with every status change it went through.
+pub fn history(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<HistoryInfo>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if info.is_some() {
+        let database = database.lock().unwrap();
+        return match database.find_mark_by_id(login.uid) {
+            Some(incident) => HttpResponse::Ok().json(History {
+                incident,
+                transitions: database.find_transitions(login.uid),
+            }),
+            None => result(&mut HttpResponse::NotFound(), "Mark not found !")
+        };
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+#[test]
+fn test_incident_transitions() {
+    use IncidentStatus::*;
+    assert!(Reported.can_become(Dispatched));
+    assert!(Dispatched.can_become(OnScene));
+    assert!(OnScene.can_become(Resolved));
+    assert!(!OnScene.can_become(Dispatched));
+    assert!(!Resolved.can_become(Reported));
+    assert!(!FalseAlarm.can_become(Cancelled));
+    assert!(Cancelled.is_closed() && !OnScene.is_closed());
+    assert_eq!(IncidentStatus::parse("false_alarm"), Some(FalseAlarm));
+    assert_eq!(IncidentStatus::parse("closed"), None);
+}
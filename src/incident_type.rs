@@ -0,0 +1,200 @@
+use crate::database::{DatabaseAccess, IncidentType, OperatorMark, Position};
+use std::sync::{Mutex, Arc};
+use actix_web::web::{Data, Json};
+use serde::{Deserialize, Serialize};
+use actix_web::{HttpRequest, Responder, HttpResponse};
+use postgres::error::UNIQUE_VIOLATION;
+use log::error;
+use uuid::Uuid;
+use crate::audit::{self, Auditor};
+use crate::incident::IncidentStatus;
+use crate::result;
+
+// what a station can send: its crew and its drones
+pub const UNIT_TYPES: [&str; 2] = ["officer", "drone"];
+
+#[derive(Deserialize)]
+pub struct DeleteIncidentTypeInfo {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct IncidentTypes {
+    inner: Vec<IncidentType>
+}
+
+// the catalogue a new database starts with
+pub fn defaults() -> Vec<IncidentType> {
+    let kind = |code: &str, name: &str, priority, units, unit_types: &[&str]| IncidentType {
+        code: code.to_string(),
+        name: name.to_string(),
+        priority,
+        units,
+        unit_types: unit_types.iter().map(|t| t.to_string()).collect(),
+    };
+    vec![
+        kind("traffic_accident", "Traffic accident", 1, 2, &["officer"]),
+        kind("fire", "Fire", 2, 3, &["officer", "drone"]),
+        kind("robbery", "Robbery", 3, 4, &["officer", "drone"]),
+    ]
+}
+
+fn validate(kind: &IncidentType) -> Result<(), &'static str> {
+    if kind.code.is_empty() || !kind.code.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        return Err("Code must be lowercase letters, digits and underscores !");
+    }
+    if kind.name.trim().is_empty() {
+        return Err("Name must not be empty !");
+    }
+    if kind.priority < 0 {
+        return Err("Priority must not be negative !");
+    }
+    if kind.units < 1 {
+        return Err("At least one unit is required !");
+    }
+    if kind.unit_types.iter().any(|t| !UNIT_TYPES.contains(&t.as_str())) {
+        return Err("Unit types must be officer and/or drone !");
+    }
+    // stations always send officers, drones come on top where the type takes them
+    if !kind.unit_types.iter().any(|t| t == "officer") {
+        return Err("Unit types must include officer !");
+    }
+    Ok(())
+}
+
+/// A new incident of type `kind` (looked up in the catalogue) or, untyped, of the given
+/// level needing one unit more than it. A level given with a type overrides its priority.
+pub fn new_mark(database: &DatabaseAccess, position: Position, kind: Option<&str>, level: Option<i32>, drone: bool, desc: String) -> Result<OperatorMark, &'static str> {
+    if level.is_some_and(|level| level < 0) {
+        return Err("Level must not be negative !");
+    }
+    let mut mark = OperatorMark {
+        position,
+        height: position.z,
+        level: 0,
+        desc,
+        drone,
+        // assigned by the database
+        uid: Uuid::nil(),
+        status: IncidentStatus::Reported,
+        kind: None,
+        units: 0,
+    };
+    match (kind, level) {
+        (Some(code), _) => {
+            let kind = database.find_incident_type(code).ok_or("Unknown incident type !")?;
+            classify(&mut mark, &kind);
+        }
+        (None, Some(_)) => {}
+        (None, None) => return Err("Level or type required !"),
+    }
+    if let Some(level) = level {
+        mark.level = level;
+    }
+    if mark.kind.is_none() {
+        mark.units = mark.level + 1;
+    }
+    Ok(mark)
+}
+
+/// Gives an incident the priority, units and unit types of `kind`; the dispatcher sends
+/// drones only to incidents taking them.
+pub fn classify(mark: &mut OperatorMark, kind: &IncidentType) {
+    mark.kind = Some(kind.code.clone());
+    mark.level = kind.priority;
+    mark.units = kind.units;
+    mark.drone = mark.drone || kind.unit_types.iter().any(|t| t == "drone");
+}
+
+pub fn list_incident_types(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(_i) = info {
+        return HttpResponse::Ok().content_type("application/json").body(
+            serde_json::to_string(&IncidentTypes {
+                inner: database.lock().unwrap().find_incident_types()
+            }).unwrap()
+        );
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn add_incident_type(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<IncidentType>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Err(err) = validate(&login) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            return match db.add_incident_type(&login) {
+                Ok(_) => {
+                    Auditor::new(&i, ip).record(&db, "create", "incident_type", &login.code, None, Some(&*login));
+                    result(&mut HttpResponse::Ok(), "success")
+                }
+                Err(ref err) if err.code() == Some(&UNIQUE_VIOLATION) =>
+                    result(&mut HttpResponse::Conflict(), "Incident type already exists !"),
+                Err(err) => {
+                    error!("failed to add incident type {}: {}", login.code, err);
+                    result(&mut HttpResponse::InternalServerError(), "failed")
+                }
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+/// Changes an incident type; incidents already reported keep what they were given.
+pub fn update_incident_type(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<IncidentType>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Err(err) = validate(&login) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            let before = match db.find_incident_type(&login.code) {
+                Some(kind) => kind,
+                None => return result(&mut HttpResponse::NotFound(), "Incident type not found !")
+            };
+            if db.update_incident_type(&login) {
+                Auditor::new(&i, ip).record(&db, "update", "incident_type", &login.code, Some(&before), Some(&*login));
+            }
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn delete_incident_type(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<DeleteIncidentTypeInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let db = database.lock().unwrap();
+            let before = db.find_incident_type(&login.code);
+            if db.delete_incident_type(&login.code) {
+                Auditor::new(&i, ip).record(&db, "delete", "incident_type", &login.code, before.as_ref(), None);
+            }
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+#[test]
+fn test_incident_type_validation() {
+    defaults().iter().for_each(|kind| assert!(validate(kind).is_ok()));
+    let mut kind = defaults().remove(0);
+    kind.code = "Traffic Accident".to_string();
+    assert!(validate(&kind).is_err());
+    kind.code = "traffic_accident".to_string();
+    kind.units = 0;
+    assert!(validate(&kind).is_err());
+    kind.units = 2;
+    kind.unit_types.push("helicopter".to_string());
+    assert!(validate(&kind).is_err());
+    kind.unit_types = vec!["drone".to_string()];
+    assert!(validate(&kind).is_err());
+}
@@ -7,13 +7,20 @@ use futures::{Stream, Future};
 use std::fs;
 use futures::future::{Either, err};
 use std::io::Write;
+use std::path::PathBuf;
+use crate::config::Config;
+use log::error;
+use crate::audit::{self, Auditor};
 
 pub fn init_token(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest, data: actix_web::web::Json<UnifiedData>) -> impl Responder {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         let guard = database.lock().unwrap();
         if i.user_type == 1 && !guard.try_init() {
             guard.feed_init(data.0.clone());
+            // the token itself stays out of the audit log
+            Auditor::new(&i, ip).record::<()>(&guard, "create", "init_data", "3ddstoken", None, None);
             return HttpResponse::Ok().content_type("application/json").body("{\"result\": true}");
         }
     }
@@ -25,13 +32,13 @@ pub fn request_unified_data(database: Data<Arc<Mutex<DatabaseAccess>>>) -> impl
         .body(serde_json::to_string(&database.lock().unwrap().load_init()).unwrap())
 }
 
-pub fn save_file(field: Field, file_path_string: &str, auth: bool) -> impl Future<Item=i64, Error=Error> {
+pub fn save_file(field: Field, file_path: PathBuf, auth: bool) -> impl Future<Item=i64, Error=Error> {
     if !auth {
         return Either::A(err(error::ErrorForbidden(
             "Not authenticated !"
         )));
     }
-    let file = match fs::File::create(file_path_string) {
+    let file = match fs::File::create(file_path) {
         Ok(file) => file,
         Err(e) => return Either::A(err(error::ErrorInternalServerError(e))),
     };
@@ -42,7 +49,7 @@ pub fn save_file(field: Field, file_path_string: &str, auth: bool) -> impl Futur
                 // on threadpool
                 web::block(move || {
                     file.write_all(bytes.as_ref()).map_err(|e| {
-                        println!("file.write_all failed: {:?}", e);
+                        error!("file.write_all failed: {:?}", e);
                         MultipartError::Payload(error::PayloadError::Io(e))
                     })?;
                     acc += bytes.len() as i64;
@@ -57,48 +64,52 @@ pub fn save_file(field: Field, file_path_string: &str, auth: bool) -> impl Futur
             })
             .map(|(_, acc)| acc)
             .map_err(|e| {
-                println!("save_file failed, {:?}", e);
+                error!("save_file failed, {:?}", e);
                 error::ErrorInternalServerError(e)
             }),
     )
 }
 
-pub fn upload_road_data(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest, multipart: Multipart) -> impl Future<Item=HttpResponse, Error=Error> {
+pub fn upload_road_data(database: Data<Arc<Mutex<DatabaseAccess>>>, config: Data<Config>, request: HttpRequest, multipart: Multipart) -> impl Future<Item=HttpResponse, Error=Error> {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
-    let mut auth = false;
-    if let Some(i) = info {
-        if i.user_type == 1 {
-            auth = true;
-        }
-    }
+    let auditor = info.filter(|i| i.user_type == 1).map(|i| Auditor::new(&i, ip));
+    let auth = auditor.is_some();
     multipart
         .map_err(error::ErrorInternalServerError)
-        .map(move |field| save_file(field, "road_data.geojson", auth).into_stream())
+        .map(move |field| save_file(field, config.data_path("road_data.geojson"), auth).into_stream())
         .flatten()
         .collect()
-        .map(|sizes| HttpResponse::Ok().json(sizes))
+        .map(move |sizes| {
+            if let Some(auditor) = auditor {
+                auditor.record(&database.lock().unwrap(), "upload", "road_data", "road_data.geojson", None, Some(&sizes));
+            }
+            HttpResponse::Ok().json(sizes)
+        })
         .map_err(|e| {
-            println!("failed: {}", e);
+            error!("road data upload failed: {}", e);
             e
         })
 }
 
-pub fn upload_point_data(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest, multipart: Multipart) -> impl Future<Item=HttpResponse, Error=Error> {
+pub fn upload_point_data(database: Data<Arc<Mutex<DatabaseAccess>>>, config: Data<Config>, request: HttpRequest, multipart: Multipart) -> impl Future<Item=HttpResponse, Error=Error> {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
-    let mut auth = false;
-    if let Some(i) = info {
-        if i.user_type == 1 {
-            auth = true;
-        }
-    }
+    let auditor = info.filter(|i| i.user_type == 1).map(|i| Auditor::new(&i, ip));
+    let auth = auditor.is_some();
     multipart
         .map_err(error::ErrorInternalServerError)
-        .map(move |field| save_file(field, "point_data.geojson", auth).into_stream())
+        .map(move |field| save_file(field, config.data_path("point_data.geojson"), auth).into_stream())
         .flatten()
         .collect()
-        .map(|sizes| HttpResponse::Ok().json(sizes))
+        .map(move |sizes| {
+            if let Some(auditor) = auditor {
+                auditor.record(&database.lock().unwrap(), "upload", "point_data", "point_data.geojson", None, Some(&sizes));
+            }
+            HttpResponse::Ok().json(sizes)
+        })
         .map_err(|e| {
-            println!("failed: {}", e);
+            error!("point data upload failed: {}", e);
             e
         })
 }
@@ -0,0 +1,86 @@
+use actix_web::dev::{Service, Transform, ServiceRequest, ServiceResponse};
+use actix_web::Error;
+use actix_web::http::{HeaderName, HeaderValue};
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use log::LevelFilter;
+use std::io::Write;
+use std::str::FromStr;
+use crate::config::LogConfig;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// access log line, the request id is echoed from the response header
+pub const ACCESS_LOG_FORMAT: &str = "%{X-Request-Id}o %a \"%r\" %s %b %Dms";
+
+/// Installs the global logger, either as plain text lines or one JSON object per line.
+pub fn init(config: &LogConfig) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::from_str(&config.level).unwrap_or(LevelFilter::Info));
+    if config.json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "time": buf.timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Tags every request with an id (kept from an incoming `X-Request-Id` header
+/// or freshly generated) and echoes it back in the response.
+pub struct RequestId;
+
+impl<S, B> Transform<S> for RequestId
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware { service })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item=Self::Response, Error=Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let id = req.headers().get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 64)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_simple().to_string());
+        Box::new(self.service.call(req).map(move |mut res| {
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+            }
+            res
+        }))
+    }
+}
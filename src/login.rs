@@ -4,7 +4,55 @@ use actix_web::web::*;
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 use crate::database::DatabaseAccess;
-use actix_web::cookie::CookieBuilder;
+use actix_web::cookie::{Cookie, CookieBuilder, SameSite};
+use crate::csrf::CSRF_COOKIE;
+use log::warn;
+use crate::result;
+
+// failed attempts tolerated per username / ip before locking out
+const MAX_LOGIN_FAILURES: i32 = 5;
+// failures older than this (in seconds) no longer count
+const FAILURE_WINDOW: i64 = 15 * 60;
+const BASE_LOCKOUT: i64 = 30;
+const MAX_LOCKOUT: i64 = 60 * 60;
+
+/// Attributes applied to the cookies issued at login, see `[session]` in the configuration.
+#[derive(Clone)]
+pub struct CookieConfig {
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            http_only: true,
+            secure: false,
+            same_site: SameSite::Strict,
+        }
+    }
+}
+
+impl CookieConfig {
+    // the csrf cookie has to stay readable by the frontend scripts
+    fn build(&self, name: &'static str, value: String, http_only: bool) -> Cookie<'static> {
+        CookieBuilder::new(name, value).path("/")
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .finish()
+    }
+}
+
+pub fn parse_same_site(value: &str) -> Result<SameSite, String> {
+    match &value.to_lowercase()[..] {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => Err(format!("SameSite must be one of strict, lax or none, got '{}'", value))
+    }
+}
 
 #[derive(Deserialize)]
 pub struct LoginInfo {
@@ -15,7 +63,9 @@ pub struct LoginInfo {
 
 #[derive(Serialize)]
 pub struct LoginResult {
-    result: &'static str
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    must_change_password: Option<bool>,
 }
 
 pub fn get_login(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> Option<crate::database::LoginInfo> {
@@ -38,38 +88,103 @@ pub fn get_login_type(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpR
     HttpResponse::Ok().content_type("application/json").body("{\"type\": -1}")
 }
 
-pub fn user_login(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<LoginInfo>) -> impl Responder {
+fn throttle_keys(name: &str, request: &HttpRequest) -> (Vec<String>, String) {
+    let ip = crate::audit::client_ip(request);
+    (vec![format!("user:{}", name), format!("ip:{}", ip)], ip)
+}
+
+// every failure past the threshold doubles the lockout, up to the cap
+fn lockout_duration(failures: i32) -> Option<i64> {
+    if failures < MAX_LOGIN_FAILURES {
+        None
+    } else {
+        let exponent = (failures - MAX_LOGIN_FAILURES).min(16) as u32;
+        Some((BASE_LOCKOUT * 2i64.pow(exponent)).min(MAX_LOCKOUT))
+    }
+}
+
+fn login_result(result: &'static str) -> String {
+    serde_json::to_string(&LoginResult { result, must_change_password: None }).unwrap()
+}
+
+pub fn user_login(database: Data<Arc<Mutex<DatabaseAccess>>>, cookies: Data<CookieConfig>, login: Json<LoginInfo>, request: HttpRequest) -> impl Responder {
     let db = database.lock().unwrap();
-    let user = db.find_user(login.name.clone());
-    match user {
-        None => {
-            HttpResponse::Ok().content_type("application/json").body(serde_json::to_string(&LoginResult {
-                result: "User not found !"
-            }).unwrap())
-        }
+    let now = crate::unix_timestamp();
+    let (keys, ip) = throttle_keys(&login.name, &request);
+    if let Some(until) = keys.iter().filter_map(|key| db.find_login_lock(key, now)).max() {
+        return HttpResponse::TooManyRequests().content_type("application/json")
+            .header("Retry-After", (until - now).to_string())
+            .body(login_result("Too many failed attempts, try again later !"));
+    }
+    let failure = match db.find_user(login.name.clone()) {
+        None => "User not found !",
+        Some(ref user) if user.passwd != login.passwd => "Password is wrong !",
+        Some(ref user) if user.user_type != login.user_type => "User not found !",
+        Some(ref user) if user.disabled => "Account is disabled !",
         Some(user) => {
-            if user.passwd == login.passwd {
-                if user.user_type == login.user_type {
-                    let uuid = uuid::Uuid::new_v4().to_string();
-                    db.add_login(crate::database::LoginInfo {
-                        username: user.username.clone(),
-                        user_type: login.user_type,
-                        token: uuid.to_string(),
-                    });
-                    HttpResponse::Ok().content_type("application/json").cookie(CookieBuilder::new("sess", uuid).path("/").secure(false).finish()).body(serde_json::to_string(&LoginResult {
-                        result: "success"
-                    }).unwrap())
-                } else {
-                    HttpResponse::Ok().content_type("application/json").body(serde_json::to_string(&LoginResult {
-                        result: "User not found !"
-                    }).unwrap())
+            db.clear_login_failures(&keys[0]);
+            let uuid = uuid::Uuid::new_v4().to_string();
+            db.add_login(crate::database::LoginInfo {
+                username: user.username.clone(),
+                user_type: login.user_type,
+                token: uuid.to_string(),
+            });
+            let csrf = uuid::Uuid::new_v4().to_simple().to_string();
+            return HttpResponse::Ok().content_type("application/json")
+                .cookie(cookies.build("sess", uuid, cookies.http_only))
+                .cookie(cookies.build(CSRF_COOKIE, csrf, false))
+                .body(serde_json::to_string(&LoginResult {
+                    result: "success",
+                    must_change_password: Some(user.must_change),
+                }).unwrap());
+        }
+    };
+    for key in keys.iter() {
+        let failures = db.record_login_failure(key, now, FAILURE_WINDOW);
+        if let Some(duration) = lockout_duration(failures) {
+            db.lock_login(key, failures, now + duration, &ip);
+            warn!("login locked for {} after {} failures ({}s)", key, failures, duration);
+        }
+    }
+    HttpResponse::Ok().content_type("application/json").body(login_result(failure))
+}
+
+#[derive(Deserialize)]
+pub struct UnlockInfo {
+    username: Option<String>,
+    ip: Option<String>,
+}
+
+pub fn unlock_login(database: Data<Arc<Mutex<DatabaseAccess>>>, unlock: Json<UnlockInfo>, request: HttpRequest) -> impl Responder {
+    let ip = crate::audit::client_ip(&request);
+    let info = get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let db = database.lock().unwrap();
+            let keys = unlock.username.iter().map(|name| format!("user:{}", name))
+                .chain(unlock.ip.iter().map(|ip| format!("ip:{}", ip)))
+                .collect::<Vec<_>>();
+            if !keys.is_empty() {
+                let auditor = crate::audit::Auditor::new(&i, ip);
+                for key in keys.iter() {
+                    db.clear_login_failures(key);
+                    auditor.record::<()>(&db, "unlock", "login_lock", key, None, None);
                 }
-            } else {
-                HttpResponse::Ok().content_type("application/json").body(serde_json::to_string(&LoginResult {
-                    result: "Password is wrong !"
-                }).unwrap())
+                return result(&mut HttpResponse::Ok(), "success");
             }
         }
     }
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
+pub fn list_lockouts(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
+    let info = get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            return HttpResponse::Ok().content_type("application/json").body(
+                serde_json::to_string(&database.lock().unwrap().find_lockouts()).unwrap()
+            );
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
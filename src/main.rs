@@ -1,6 +1,9 @@
-use actix_web::{HttpServer, App, Responder};
+use actix_web::{HttpServer, App, Responder, middleware};
 use actix_web::web::*;
 
+#[macro_use]
+extern crate prometheus;
+
 mod database;
 mod login;
 mod user;
@@ -9,18 +12,44 @@ mod police_station;
 mod operator_mark;
 mod init;
 mod dispatcher;
-
-use actix_web_static_files;
+mod session;
+mod csrf;
+mod rotation;
+mod config;
+mod tls;
+mod logging;
+mod metrics;
+mod health;
+mod shutdown;
+mod events;
+mod sync;
+mod incident;
+mod incident_type;
+mod audit;
+mod crew;
+mod shift;
+mod overrides;
+mod coverage;
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use crate::database::DatabaseAccess;
 use sha2::{Sha256, Digest};
 use crate::dispatcher::DispatcherService;
+use crate::session::SessionSweeper;
+use crate::sync::ChangeLogTrimmer;
+use crate::config::Config;
+use crate::csrf::CsrfGuard;
+use crate::rotation::RequirePasswordRotation;
 use actix::Actor;
 use crate::dispatch::{Dispatcher, parse_road_data, construct_topology, offline_bellman_ford};
 use std::io::{BufReader, Read};
 use std::process::exit;
+use std::time::{Duration, UNIX_EPOCH};
+use log::{info, warn, error};
+use crate::logging::{RequestId, ACCESS_LOG_FORMAT};
+use crate::metrics::RequestMetrics;
+use crate::events::EventHub;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
@@ -40,8 +69,8 @@ fn main_page(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -
     }
 }
 
-fn load_road() -> impl Responder {
-    if let Ok(file) = std::fs::File::open("road_data.geojson") {
+fn load_road(config: Data<Config>) -> impl Responder {
+    if let Ok(file) = std::fs::File::open(config.data_path("road_data.geojson")) {
         let mut string = String::new();
         BufReader::new(file).read_to_string(&mut string).unwrap();
         HttpResponse::Ok().body(string)
@@ -50,53 +79,90 @@ fn load_road() -> impl Responder {
     }
 }
 
+/// The `{"result": ...}` body handlers answer with, the message escaped as JSON.
+pub fn result(response: &mut actix_web::dev::HttpResponseBuilder, result: &str) -> HttpResponse {
+    response.json(serde_json::json!({ "result": result }))
+}
+
 pub fn fast_sha256(data: &str) -> String {
     let mut sha = Sha256::new();
     sha.input(data.as_bytes());
     hex::encode(sha.result())
 }
 
-#[allow(unreachable_code)]
+pub fn unix_timestamp() -> i64 {
+    std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
 fn main() {
-    println!("Welcome use police dispatch system v1.0");
-    println!("  Copyleft by Central South University");
-    println!("  Authors:");
-    println!("      Frontend: Siyang Wen, Dazhou Ping");
-    println!("      Backend: Xuanxiang Wang, Xiaoyong Tan");
-    println!("  Powered by DataEarth© Cesium® system");
+    let (config, print_config) = Config::load().unwrap_or_else(|errors| {
+        errors.iter().for_each(|err| eprintln!("invalid configuration: {}", err));
+        exit(1);
+    });
+    if print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+    logging::init(&config.log);
+    info!("police dispatch system v1.0 - Central South University, powered by DataEarth Cesium");
+    let cookie_config = config.cookies();
     let sys = actix::System::new("actix-server");
     let database = database::DatabaseAccess::new(
-        include_str!("../database.auth")
+        &config.database.url
     ).unwrap_or_else(|err| {
-        eprintln!("无法连接到Postgres数据库 : {}", err);
+        error!("unable to connect to postgres: {}", err);
         exit(1);
-        panic!()
-    });
+    }).with_session_ttl(config.session.ttl);
     database.init();
+    database.bootstrap_admin(config.admin_password.clone());
     let mut init = database.try_init();
-    let file = std::fs::File::open("point_data.geojson");
-    let dispatcher = if file.is_ok() && init {
-        println!("加载空间拓扑数据中...");
+    let file = std::fs::File::open(config.data_path("point_data.geojson"));
+    let dispatcher = if let (Ok(file), true) = (file, init) {
+        info!("loading road topology ...");
         let mut string = String::new();
-        BufReader::new(file.unwrap()).read_to_string(&mut string).unwrap();
+        BufReader::new(file).read_to_string(&mut string).unwrap();
         let roadmap = parse_road_data(&string).unwrap();
         let graph = construct_topology(&roadmap);
         let optimized = offline_bellman_ford(&graph);
-        Dispatcher::new(graph, optimized)
+        Dispatcher::new(graph, optimized, config.dispatch.factor)
     } else {
         init = false;
-        Dispatcher::new(vec![], vec![])
+        Dispatcher::new(vec![], vec![], config.dispatch.factor)
     };
     let arc = Arc::new(Mutex::new(database));
     let service_arc = arc.clone();
-    let service = DispatcherService::new(service_arc.clone(), dispatcher.clone(), init).start();
+    let events = EventHub::new(arc.clone()).start();
+    let service = DispatcherService::new(service_arc.clone(), dispatcher.clone(), events.clone(), init, config.dispatch.utc_offset).start();
+    SessionSweeper::new(arc.clone()).start();
+    ChangeLogTrimmer::new(arc.clone()).start();
 
     let wrapped_db = Data::new(arc.clone());
-    HttpServer::new(move || {
+    let wrapped_config = Data::new(config.clone());
+    let wrapped_roads = Data::new(dispatcher.clone());
+    let wrapped_service = service.clone();
+    let hsts = Some(config.tls.hsts_max_age)
+        .filter(|max_age| config.tls_enabled() && *max_age > 0)
+        .map(tls::hsts_header);
+    let server = HttpServer::new(move || {
         let generated = generate();
+        let mut headers = middleware::DefaultHeaders::new();
+        if let Some(hsts) = &hsts {
+            headers = headers.header("Strict-Transport-Security", hsts.as_str());
+        }
         App::new()
-            .register_data(Data::new(service.clone()))
+            .wrap(headers)
+            .wrap(CsrfGuard)
+            .wrap(RequirePasswordRotation(arc.clone()))
+            // outermost, so rejected requests are tagged and logged as well
+            .wrap(RequestMetrics)
+            .wrap(RequestId)
+            .wrap(middleware::Logger::new(ACCESS_LOG_FORMAT))
+            .register_data(Data::new(wrapped_service.clone()))
+            .register_data(Data::new(events.clone()))
+            .register_data(Data::new(cookie_config.clone()))
             .register_data(wrapped_db.clone())
+            .register_data(wrapped_config.clone())
+            .register_data(wrapped_roads.clone())
             .service(actix_web_static_files::ResourceFiles::new(
                 "/static",
                 generated,
@@ -106,28 +172,95 @@ fn main() {
             .route("/user/delete", post().to(user::delete_user))
             .route("/user/logout", post().to(user::logout))
             .route("/user/type", post().to(login::get_login_type))
+            .route("/user/sessions", post().to(user::list_sessions))
+            .route("/user/unlock", post().to(login::unlock_login))
+            .route("/user/lockouts", post().to(login::list_lockouts))
             .route("/init/check", post().to(init_check))
             .route("/init/ps", post().to(police_station::add_police_station))
             .route("/user/add", post().to(user::add_user))
-            .route("/data/mark", post().to(operator_mark::add_mark))
+            .route("/user/list", post().to(user::list_users))
+            .route("/user/get", post().to(user::get_user))
+            .route("/user/update", post().to(user::update_user))
+            .route("/user/disable", post().to(user::disable_user))
+            .route("/user/passwd", post().to(user::change_password))
+            .route("/data/mark", post().to_async(operator_mark::add_mark))
             .route("/data/init", post().to(init::init_token))
             .route("/data/request", post().to(init::request_unified_data))
             .route("/data/get_mark", post().to(operator_mark::list_mark))
             .route("/mark/delete", post().to(operator_mark::delete_mark))
+            .route("/mark/update", post().to(operator_mark::edit_mark))
+            .route("/mark/status", post().to(incident::change_status))
+            .route("/mark/history", post().to(incident::history))
+            .route("/dispatch/assign", post().to_async(overrides::assign))
+            .route("/dispatch/cancel", post().to_async(overrides::cancel))
+            .route("/dispatch/exclude", post().to_async(overrides::exclude))
+            .route("/data/get_types", post().to(incident_type::list_incident_types))
+            .route("/types/add", post().to(incident_type::add_incident_type))
+            .route("/types/update", post().to(incident_type::update_incident_type))
+            .route("/types/delete", post().to(incident_type::delete_incident_type))
+            .route("/data/get_crew", post().to(crew::list_crew))
+            .route("/crew/add", post().to(crew::add_crew))
+            .route("/crew/update", post().to(crew::update_crew))
+            .route("/crew/delete", post().to(crew::delete_crew))
+            .route("/data/get_equipment", post().to(crew::list_equipment))
+            .route("/equipment/add", post().to(crew::add_equipment))
+            .route("/equipment/update", post().to(crew::update_equipment))
+            .route("/equipment/delete", post().to(crew::delete_equipment))
             .route("/data/get_ps", post().to(police_station::list_police_station))
+            .route("/ps/shifts", post().to(shift::set_shifts))
+            .route("/data/get_shifts", post().to(shift::list_shifts))
+            .route("/data/coverage", post().to(coverage::isochrones))
+            .route("/ps/update", post().to(police_station::update_police_station))
             .route("/ps/delete", post().to(police_station::delete_police_station))
+            .route("/data/mark/preview", post().to_async(operator_mark::preview_mark))
             .route("/data/mark/ping", post().to(operator_mark::update_mark))
             .route("/upload/road", post().to_async(init::upload_road_data))
             .route("/upload/point", post().to_async(init::upload_point_data))
             .route("/route", post().to(operator_mark::list_routes))
             .route("/data/road.geojson", get().to(load_road))
-    })
-        .bind("127.0.0.1:80").unwrap()
-        .start();
-    println!("初始化是否完成 : {}", init);
+            .route("/metrics", get().to(metrics::export))
+            .route("/events", get().to(events::subscribe))
+            .route("/sync", get().to(sync::sync))
+            .route("/audit", get().to(audit::list))
+            .route("/audit/export", get().to(audit::export))
+            .route("/healthz", get().to(health::alive))
+            .route("/readyz", get().to_async(health::ready))
+    });
+    let server = server.disable_signals().shutdown_timeout(config.server.shutdown_timeout);
+    let server = match config.server.workers {
+        Some(workers) => server.workers(workers),
+        None => server
+    };
+    let bound = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => {
+            let rustls = tls::load_rustls_config(cert, key).unwrap_or_else(|err| {
+                error!("unable to load tls certificate: {}", err);
+                exit(1);
+            });
+            server.bind_rustls(config.bind_address(), rustls)
+        }
+        _ => server.bind(config.bind_address())
+    };
+    let mut servers = vec![bound.unwrap_or_else(|err| {
+        error!("unable to bind {}: {}", config.bind_address(), err);
+        exit(1);
+    }).start()];
+    if let Some(redirect_port) = config.tls.redirect_port {
+        let https_port = config.server.port;
+        servers.push(HttpServer::new(move || {
+            App::new().default_service(to(tls::redirect_to_https(https_port)))
+        })
+            .disable_signals()
+            .bind(format!("{}:{}", config.server.address, redirect_port)).unwrap_or_else(|err| {
+                error!("unable to bind https redirect port {}: {}", redirect_port, err);
+                exit(1);
+            })
+            .start());
+    }
+    shutdown::on_signal(servers, service, Duration::from_secs(config.server.shutdown_timeout));
     if !init {
-        println!("请初始化数据并重启系统来使其完全工作 ！")
+        warn!("system is not initialized, upload the map data and restart to enable dispatching");
     }
-    println!("系统已启动 ...");
+    info!("listening on {}{}", config.bind_address(), if config.tls_enabled() { " (https)" } else { "" });
     sys.run().expect("Unable to start actix system");
 }
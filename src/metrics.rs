@@ -0,0 +1,128 @@
+use actix_web::dev::{Service, Transform, ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpRequest, HttpResponse, Responder};
+use actix_web::web::Data;
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramVec, Histogram, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::database::DatabaseAccess;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "dataearth_http_requests_total", "HTTP requests by route, method and status", &["route", "method", "status"]).unwrap();
+    pub static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "dataearth_http_request_duration_seconds", "HTTP request latency by route", &["route"]).unwrap();
+    pub static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "dataearth_db_query_duration_seconds", "Database statement latency by statement kind and table", &["statement"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]).unwrap();
+    pub static ref DISPATCHER_MAILBOX: IntGauge = register_int_gauge!(
+        "dataearth_dispatcher_mailbox_depth", "Workloads queued for the dispatcher actor").unwrap();
+    pub static ref ACTIVE_INCIDENTS: IntGauge = register_int_gauge!(
+        "dataearth_active_incidents", "Incidents currently marked on the map").unwrap();
+    pub static ref UNMET_DEMAND: IntGauge = register_int_gauge!(
+        "dataearth_unmet_demand_units", "Units requested by incidents but not yet assigned").unwrap();
+    pub static ref STATION_POWER: IntGaugeVec = register_int_gauge_vec!(
+        "dataearth_station_available_power", "Units currently available per police station", &["station"]).unwrap();
+    pub static ref ROUTE_COMPUTATION: Histogram = register_histogram!(
+        "dataearth_route_computation_seconds", "Time spent computing a dispatch route",
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]).unwrap();
+}
+
+/// Labels a statement by its verb and the table it touches, e.g. `select login_data`.
+pub fn statement_label(sql: &str) -> String {
+    let words: Vec<String> = sql.split_whitespace().take(32).map(|w| w.to_lowercase()).collect();
+    let verb = words.first().map(|w| w.as_str()).unwrap_or("unknown");
+    let after = |keyword: &str| words.iter().position(|w| w == keyword).and_then(|i| words.get(i + 1));
+    let table = match verb {
+        "select" | "delete" => after("from"),
+        "insert" => after("into"),
+        "update" => words.get(1),
+        _ => None
+    };
+    match table {
+        Some(table) => format!("{} {}", verb, table.trim_matches(|c: char| !c.is_alphanumeric() && c != '_')),
+        None => verb.to_string()
+    }
+}
+
+// collapses the request path to a bounded set of label values
+fn route_label(request: &HttpRequest) -> String {
+    let path = request.path();
+    if path.starts_with("/static/") {
+        "/static".to_string()
+    } else if request.resource_map().has_resource(path) {
+        path.to_string()
+    } else {
+        "unmatched".to_string()
+    }
+}
+
+/// `/metrics`, in the Prometheus text exposition format.
+pub fn export(database: Data<Arc<Mutex<DatabaseAccess>>>) -> impl Responder {
+    ACTIVE_INCIDENTS.set(database.lock().unwrap().count_marks());
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+    HttpResponse::Ok().content_type(encoder.format_type()).body(buffer)
+}
+
+/// Counts and times every request by route, method and status.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware { service })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item=Self::Response, Error=Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        Box::new(self.service.call(req).map(move |res| {
+            let route = route_label(res.request());
+            HTTP_REQUESTS.with_label_values(&[&route, &method, res.status().as_str()]).inc();
+            HTTP_DURATION.with_label_values(&[&route]).observe(started.elapsed().as_secs_f64());
+            res
+        }))
+    }
+}
+
+#[test]
+fn test_statement_label() {
+    assert_eq!(statement_label("SELECT * FROM login_data WHERE token=$1"), "select login_data");
+    assert_eq!(statement_label("INSERT INTO dispatch_routes (belong, xs, ys) VALUES ($1, $2, $3)"), "insert dispatch_routes");
+    assert_eq!(statement_label("UPDATE user_data SET type=$2 WHERE name=$1"), "update user_data");
+    assert_eq!(statement_label("DELETE FROM login_data\n WHERE expires_at <= $1"), "delete login_data");
+    assert_eq!(statement_label("CREATE TABLE IF NOT EXISTS init_data ()"), "create");
+}
@@ -2,23 +2,65 @@ use crate::database::{DatabaseAccess, Position, OperatorMark};
 use std::sync::{Mutex, Arc};
 use actix_web::web::{Data, Json};
 use serde::{Deserialize, Serialize};
-use actix_web::{HttpRequest, Responder, HttpResponse};
-use std::time::UNIX_EPOCH;
+use actix_web::{Error, HttpRequest, Responder, HttpResponse};
+use futures::Future;
+use futures::future::{ok, Either};
+use log::error;
+use std::time::Duration;
+use uuid::Uuid;
 use actix::Addr;
-use crate::dispatcher::DispatcherService;
-use crate::dispatch::{Workload, Coordinates};
+use crate::dispatcher::{self, DispatchResult, DispatcherService, Preview};
+use crate::dispatch::{self as routing, Workload};
+use crate::config::Config;
+use crate::events::{Event, EventHub};
+use crate::incident::{self, IncidentStatus};
+use crate::incident_type;
+use crate::audit::{self, Auditor};
+use crate::result;
+
+// how long `/data/mark` with `wait` waits for the dispatcher
+const DISPATCH_WAIT: Duration = Duration::from_secs(10);
 
 #[derive(Deserialize)]
 pub struct DeleteMarkInfo {
-    uid: i32
+    uid: Uuid,
+    reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct AddMarkInfo {
     position: Position,
-    level: i32,
+    // an incident type from the catalogue, or a level
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    level: Option<i32>,
+    #[serde(default)]
     drone: bool,
     desc: String,
+    // answer only once the dispatcher has sent units
+    #[serde(default)]
+    wait: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewMarkInfo {
+    position: Position,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    level: Option<i32>,
+    #[serde(default)]
+    drone: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMarkInfo {
+    uid: Uuid,
+    position: Option<Position>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    level: Option<i32>,
+    drone: Option<bool>,
+    desc: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -26,16 +68,64 @@ pub struct Marks {
     inner: Vec<OperatorMark>
 }
 
-pub fn delete_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<DeleteMarkInfo>, dispatcher: Data<Addr<DispatcherService>>, request: HttpRequest) -> impl Responder {
+/// Cancels an incident (reason `withdrawn` unless given); it stays in the
+/// database with its history but leaves the map.
+pub fn delete_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<DeleteMarkInfo>, dispatcher: Data<Addr<DispatcherService>>, events: Data<Addr<EventHub>>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         if i.user_type == 2 {
-            database.lock().unwrap().delete_mark(login.uid.clone());
-            dispatcher.do_send(Workload::delete(login.uid as usize));
-            return HttpResponse::Ok().content_type("application/json").body("{\"result\": \"success\"}");
+            let reason = login.reason.as_deref().unwrap_or("withdrawn");
+            return incident::close_or_advance(&database.lock().unwrap(), &dispatcher, &events,
+                                              login.uid, IncidentStatus::Cancelled, Some(reason), &Auditor::new(&i, ip));
         }
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+/// Edits an incident in place; the dispatcher sends or recalls units for
+/// the new level and reroutes the units already on their way.
+pub fn edit_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<UpdateMarkInfo>, dispatcher: Data<Addr<DispatcherService>>, events: Data<Addr<EventHub>>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 0 {
+            if login.level.is_some_and(|level| level < 0) {
+                return result(&mut HttpResponse::BadRequest(), "Level must not be negative !");
+            }
+            let database = database.lock().unwrap();
+            let mut mark = match database.find_mark_by_id(login.uid) {
+                Some(mark) => mark,
+                None => return result(&mut HttpResponse::NotFound(), "Mark not found !")
+            };
+            if mark.status.is_closed() {
+                return result(&mut HttpResponse::Conflict(), "Mark is closed !");
+            }
+            let before = mark.clone();
+            if let Some(position) = login.position {
+                mark.position = position;
+                mark.height = position.z;
+            }
+            mark.drone = login.drone.unwrap_or(mark.drone);
+            if let Some(code) = &login.kind {
+                match database.find_incident_type(code) {
+                    Some(kind) => incident_type::classify(&mut mark, &kind),
+                    None => return result(&mut HttpResponse::BadRequest(), "Unknown incident type !")
+                }
+            }
+            mark.level = login.level.unwrap_or(mark.level);
+            if mark.kind.is_none() {
+                mark.units = mark.level + 1;
+            }
+            mark.desc = login.desc.clone().unwrap_or(mark.desc);
+            database.update_mark(&mark);
+            Auditor::new(&i, ip).record(&database, "update", "mark", &login.uid.to_string(), Some(&before), Some(&mark));
+            dispatcher::change(&dispatcher, Workload::for_mark(&mark));
+            events.do_send(Event::IncidentUpdated { incident: mark });
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
 pub fn list_routes(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
@@ -48,7 +138,7 @@ pub fn list_routes(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequ
             serde_json::to_string(&routes).unwrap()
         );
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
 pub fn list_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
@@ -59,17 +149,17 @@ pub fn list_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpReques
                 .unwrap()
         );
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
-pub fn update_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest, req: Json<Vec<i32>>) -> impl Responder {
+pub fn update_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest, req: Json<Vec<Uuid>>) -> impl Responder {
     let info = crate::login::get_login(database.clone(), request);
     if let Some(_i) = info {
         let marks = database.lock().unwrap().find_mark();
         let mut remove = req.iter()
-            .filter(|v| !marks.iter().any(|p| p.uid as i32 == **v))
+            .filter(|v| !marks.iter().any(|p| p.uid == **v))
             .map(|v| OperatorMark {
-                uid: *v as u128,
+                uid: *v,
                 level: 0,
                 position: Position {
                     x: 0.0,
@@ -79,46 +169,114 @@ pub fn update_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequ
                 desc: String::new(),
                 height: 0.0,
                 drone: false,
+                status: IncidentStatus::default(),
+                kind: None,
+                units: 0,
             }).collect::<Vec<_>>();
         let append = marks.into_iter()
-            .filter(|p| !req.contains(&(p.uid as i32)));
+            .filter(|p| !req.contains(&p.uid));
         remove.extend(append);
         return HttpResponse::Ok().content_type("application/json").body(
             serde_json::to_string(&remove)
                 .unwrap()
         );
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+#[derive(Serialize)]
+struct Created {
+    result: &'static str,
+    id: Uuid,
+    // only when the request asked to wait; null if the dispatcher did not answer in time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dispatch: Option<Option<DispatchResult>>,
 }
 
-pub fn add_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<AddMarkInfo>, request: HttpRequest) -> impl Responder {
+/// Stores a new incident and hands it to the dispatcher. With `wait` the response
+/// carries the units sent to it, otherwise it returns as soon as the incident is stored.
+pub fn add_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, events: Data<Addr<EventHub>>, login: Json<AddMarkInfo>, request: HttpRequest) -> impl Future<Item=HttpResponse, Error=Error> {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         if i.user_type == 0 {
-            let uid = database.lock().unwrap().add_mark(OperatorMark {
-                position: login.position,
-                height: login.position.z,
-                level: login.level,
-                desc: login.desc.clone(),
-                drone: login.drone,
-                uid: std::time::SystemTime::now().duration_since(UNIX_EPOCH)
-                    .unwrap().as_millis(),
-            });
-            dispatcher.do_send(Workload {
-                is_remove: false,
-                id: uid as usize,
-                assign_id: uid as usize,
-                severity: login.level as usize,
-                consumption: (login.level + 1) as usize,
-                location: Coordinates::from(login.position),
-                drone: login.drone,
-            });
-            return HttpResponse::Ok().content_type("application/json").body(
-                format!(
-                    "{{\"result\": \"success\", \"id\": {}}}"
-                    , uid)
-            );
+            let database = database.lock().unwrap();
+            let mark = match incident_type::new_mark(&database, login.position, login.kind.as_deref(), login.level, login.drone, login.desc.clone()) {
+                Ok(mark) => mark,
+                Err(err) => return Either::A(ok(result(&mut HttpResponse::BadRequest(), err)))
+            };
+            let (mark, entry) = match database.add_mark(mark, &i.username) {
+                Ok(created) => created,
+                Err(err) => {
+                    error!("unable to store a new incident: {}", err);
+                    return Either::A(ok(result(&mut HttpResponse::InternalServerError(), "failed")));
+                }
+            };
+            Auditor::new(&i, ip).record(&database, "create", "mark", &mark.uid.to_string(), None, Some(&mark));
+            drop(database);
+            let id = mark.uid;
+            events.do_send(Event::IncidentCreated { incident: mark });
+            if !login.wait {
+                dispatcher::announce(&dispatcher, entry, id);
+                return Either::A(ok(HttpResponse::Ok().json(Created { result: "success", id, dispatch: None })));
+            }
+            return Either::B(Either::A(dispatcher::announce_waiting(&dispatcher, entry, id).timeout(DISPATCH_WAIT).then(move |dispatch| {
+                Ok(HttpResponse::Ok().json(Created { result: "success", id, dispatch: Some(dispatch.ok().and_then(|d| d.ok())) }))
+            })));
         }
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    Either::B(Either::B(ok(result(&mut HttpResponse::Ok(), "failed"))))
+}
+
+#[derive(Serialize)]
+struct ProposedMission {
+    source: String,
+    units: usize,
+    // the lower severity dispatch the units would be taken from
+    reassigned_from: Option<usize>,
+    route: Vec<(f64, f64)>,
+    // metres along the route and seconds to cover it
+    distance: f64,
+    eta: u64,
+}
+
+#[derive(Serialize)]
+struct DispatchPreview {
+    missions: Vec<ProposedMission>,
+    missing: usize,
+}
+
+/// `/data/mark/preview`: the units and routes the dispatcher would send to an
+/// incident reported now, without reporting it.
+pub fn preview_mark(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, config: Data<Config>, login: Json<PreviewMarkInfo>, request: HttpRequest) -> impl Future<Item=HttpResponse, Error=Error> {
+    let info = crate::login::get_login(database.clone(), request);
+    if info.is_some_and(|i| i.user_type == 0) {
+        let mark = incident_type::new_mark(&database.lock().unwrap(), login.position, login.kind.as_deref(), login.level, login.drone, String::new());
+        let mark = match mark {
+            Ok(mark) => mark,
+            Err(err) => return Either::A(ok(result(&mut HttpResponse::BadRequest(), err)))
+        };
+        // km/h to m/s
+        let speed = config.dispatch.speed / 3.6;
+        return Either::B(dispatcher.send(Preview(Workload::for_mark(&mark))).timeout(DISPATCH_WAIT).then(move |proposal| {
+            Ok(match proposal {
+                Ok(Ok(proposal)) => HttpResponse::Ok().json(DispatchPreview {
+                    missions: proposal.missions.into_iter().map(|mission| {
+                        let distance = routing::route_length(&mission.path_given);
+                        ProposedMission {
+                            source: mission.source,
+                            units: mission.power,
+                            reassigned_from: Some(mission.predecessor).filter(|p| *p != 0),
+                            route: mission.path_given,
+                            distance,
+                            eta: (distance / speed).round() as u64,
+                        }
+                    }).collect(),
+                    missing: proposal.missing,
+                }),
+                _ => result(&mut HttpResponse::ServiceUnavailable(), "Dispatcher unavailable !")
+            })
+        }));
+    }
+    Either::A(ok(result(&mut HttpResponse::Ok(), "failed")))
 }
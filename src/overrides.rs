@@ -0,0 +1,103 @@
+use actix::Addr;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web::web::{Data, Json};
+use futures::Future;
+use futures::future::{ok, Either};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+use crate::audit::{self, Auditor};
+use crate::database::DatabaseAccess;
+use crate::dispatch::Workload;
+use crate::dispatcher::{DispatchResult, DispatcherService, ManualDispatch, Override, Rejected};
+use crate::result;
+
+// how long an override waits for the dispatcher
+const OVERRIDE_WAIT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+pub struct AssignInfo {
+    uid: Uuid,
+    station: String,
+    units: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CancelInfo {
+    uid: Uuid,
+    station: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExcludeInfo {
+    uid: Uuid,
+    stations: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Applied {
+    result: &'static str,
+    dispatch: DispatchResult,
+}
+
+fn rejected(reason: Rejected) -> HttpResponse {
+    match reason {
+        Rejected::Unavailable => result(&mut HttpResponse::ServiceUnavailable(), "Dispatcher unavailable !"),
+        Rejected::UnknownStation(station) => result(&mut HttpResponse::NotFound(), &format!("Station {} not found !", station)),
+        Rejected::StationExcluded => result(&mut HttpResponse::Conflict(), "Station is excluded from this incident !"),
+        Rejected::NoUnits => result(&mut HttpResponse::Conflict(), "Station has no free units !"),
+        Rejected::NotAssigned => result(&mut HttpResponse::Conflict(), "Station has no units on this incident !"),
+        Rejected::Failed => result(&mut HttpResponse::InternalServerError(), "failed"),
+    }
+}
+
+// hands an override of an open incident to the dispatcher and records it as `action`
+fn apply(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, request: HttpRequest,
+         uid: Uuid, change: Override, action: &'static str) -> impl Future<Item=HttpResponse, Error=Error> {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    let actor = match info {
+        Some(i) if i.user_type == 0 => Auditor::new(&i, ip),
+        _ => return Either::A(ok(result(&mut HttpResponse::Ok(), "failed")))
+    };
+    let mark = match database.lock().unwrap().find_mark_by_id(uid) {
+        Some(mark) => mark,
+        None => return Either::A(ok(result(&mut HttpResponse::NotFound(), "Mark not found !")))
+    };
+    if mark.status.is_closed() {
+        return Either::A(ok(result(&mut HttpResponse::Conflict(), "Mark is closed !")));
+    }
+    let change = ManualDispatch { workload: Workload::for_mark(&mark), change, actor: actor.username().to_string() };
+    Either::B(dispatcher.send(change).timeout(OVERRIDE_WAIT).then(move |outcome| {
+        Ok(match outcome {
+            Ok(Ok(applied)) => {
+                actor.record(&database.lock().unwrap(), action, "dispatch", &uid.to_string(), Some(&applied.before), Some(&applied.after));
+                HttpResponse::Ok().json(Applied { result: "success", dispatch: applied.after })
+            }
+            Ok(Err(reason)) => rejected(reason),
+            Err(_) => rejected(Rejected::Unavailable)
+        })
+    }))
+}
+
+/// `/dispatch/assign`: sends `units` (one unless given, at most the free ones) of a station to an incident.
+pub fn assign(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, info: Json<AssignInfo>, request: HttpRequest) -> impl Future<Item=HttpResponse, Error=Error> {
+    let info = info.into_inner();
+    let change = Override::Assign { station: info.station, units: info.units.unwrap_or(1) };
+    apply(database, dispatcher, request, info.uid, change, "assign")
+}
+
+/// `/dispatch/cancel`: recalls a station's units from an incident, which is then kept away from
+/// the station; the dispatcher looks for the missing units elsewhere.
+pub fn cancel(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, info: Json<CancelInfo>, request: HttpRequest) -> impl Future<Item=HttpResponse, Error=Error> {
+    let info = info.into_inner();
+    apply(database, dispatcher, request, info.uid, Override::Cancel { station: info.station }, "cancel_mission")
+}
+
+/// `/dispatch/exclude`: pins an incident away from the given stations (none lifts the pin),
+/// recalling the units they already sent.
+pub fn exclude(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, info: Json<ExcludeInfo>, request: HttpRequest) -> impl Future<Item=HttpResponse, Error=Error> {
+    let info = info.into_inner();
+    apply(database, dispatcher, request, info.uid, Override::Exclude { stations: info.stations }, "exclude")
+}
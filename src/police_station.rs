@@ -1,8 +1,17 @@
-use crate::database::{DatabaseAccess, PoliceStation, Position};
+use crate::database::{CrewMember, DatabaseAccess, PoliceStation, Position};
 use std::sync::{Mutex, Arc};
 use actix_web::web::{Data, Json};
 use serde::{Deserialize, Serialize};
 use actix_web::{HttpRequest, Responder, HttpResponse};
+use actix::Addr;
+use postgres::error::UNIQUE_VIOLATION;
+use log::error;
+use crate::audit::{self, Auditor};
+use crate::crew::DutyStatus;
+use crate::config::Config;
+use crate::dispatch::{Coordinates, Dispatcher};
+use crate::dispatcher::{DispatcherService, StationChanged};
+use crate::result;
 
 #[derive(Deserialize)]
 pub struct DeletePoliceStationInfo {
@@ -24,20 +33,33 @@ pub struct AddPoliceStationInfo {
     drones: i32,
 }
 
+#[derive(Deserialize)]
+pub struct UpdatePoliceStationInfo {
+    id: String,
+    name: Option<String>,
+    position: Option<Position>,
+}
+
 #[derive(Serialize)]
 pub struct PoliceStations {
     inner: Vec<PoliceStation>
 }
 
-pub fn delete_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<DeletePoliceStationInfo>, request: HttpRequest) -> impl Responder {
+pub fn delete_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<DeletePoliceStationInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         if i.user_type == 1 {
-            database.lock().unwrap().delete_police_station(login.id.clone());
-            return HttpResponse::Ok().content_type("application/json").body("{\"result\": \"success\"}");
+            let db = database.lock().unwrap();
+            let before = db.find_police_station().into_iter().find(|station| station.id == login.id);
+            if db.delete_police_station(login.id.clone()) {
+                Auditor::new(&i, ip).record(&db, "delete", "station", &login.id, before.as_ref(), None);
+                dispatcher.do_send(StationChanged(login.id.clone()));
+            }
+            return result(&mut HttpResponse::Ok(), "success");
         }
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
 pub fn list_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
@@ -49,24 +71,117 @@ pub fn list_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, request:
             }).unwrap()
         );
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+// crew ids must be new and unique, as must the ids `{station}-drone-N` given to the drones
+fn check_ids(database: &DatabaseAccess, station: &PoliceStation, crew: &[CrewMember]) -> Result<(), String> {
+    let existing = database.find_crew(None);
+    for (index, member) in crew.iter().enumerate() {
+        if crew[..index].iter().any(|other| other.id == member.id) {
+            return Err(format!("Crew member {} is listed twice !", member.id));
+        }
+        if existing.iter().any(|other| other.id == member.id) {
+            return Err(format!("Crew member {} already exists !", member.id));
+        }
+    }
+    let equipment = database.find_equipment(None);
+    if let Some(clash) = (1..=station.drones.max(0)).map(|index| format!("{}-drone-{}", station.id, index))
+        .find(|id| equipment.iter().any(|other| other.id == *id)) {
+        return Err(format!("Equipment {} already exists !", clash));
+    }
+    Ok(())
+}
+
+pub fn add_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, roads: Data<Arc<Mutex<Dispatcher>>>, config: Data<Config>, login: Json<AddPoliceStationInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if login.id.trim().is_empty() {
+                return result(&mut HttpResponse::BadRequest(), "Id must not be empty !");
+            }
+            if let Err(err) = roads.lock().unwrap().check_station(Coordinates::from(login.position), config.dispatch.min_reach) {
+                return result(&mut HttpResponse::BadRequest(), &err);
+            }
+            let station = PoliceStation {
+                id: login.id.clone(),
+                name: login.name.clone(),
+                position: login.position,
+                crew: login.crew.iter().map(|crew| crew.name.clone()).collect(),
+                drones: login.drones,
+            };
+            let crew = login.crew.iter().map(|crew| CrewMember {
+                id: crew.id.clone(),
+                station: login.id.clone(),
+                name: crew.name.clone(),
+                status: DutyStatus::OnDuty,
+                capabilities: vec![],
+                shift_start: None,
+                shift_end: None,
+            }).collect::<Vec<_>>();
+            let db = database.lock().unwrap();
+            if db.find_police_station().iter().any(|ps| ps.id == station.id) {
+                return result(&mut HttpResponse::Conflict(), "Station already exists !");
+            }
+            if let Err(err) = check_ids(&db, &station, &crew) {
+                return result(&mut HttpResponse::Conflict(), &err);
+            }
+            return match db.add_police_station(station.clone(), &crew) {
+                Ok(()) => {
+                    Auditor::new(&i, ip).record(&db, "create", "station", &station.id, None, Some(&station));
+                    dispatcher.do_send(StationChanged(station.id));
+                    result(&mut HttpResponse::Ok(), "success")
+                }
+                Err(ref err) if err.code() == Some(&UNIQUE_VIOLATION) => {
+                    // added by someone else meanwhile
+                    let entity = match err.as_db().and_then(|db| db.table.as_deref()) {
+                        Some("crew_members") => "Crew member",
+                        Some("station_equipment") => "Equipment",
+                        _ => "Station",
+                    };
+                    result(&mut HttpResponse::Conflict(), &format!("{} already exists !", entity))
+                }
+                Err(err) => {
+                    error!("failed to add station {}: {}", station.id, err);
+                    result(&mut HttpResponse::InternalServerError(), "failed")
+                }
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
-pub fn add_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<AddPoliceStationInfo>, request: HttpRequest) -> impl Responder {
+/// `/ps/update`: renames or moves a station, its crew is managed through `/crew/*`.
+pub fn update_police_station(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, roads: Data<Arc<Mutex<Dispatcher>>>, config: Data<Config>, login: Json<UpdatePoliceStationInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         if i.user_type == 1 {
-            database.lock().unwrap().add_police_station(
-                PoliceStation {
-                    id: login.id.clone(),
-                    name: login.name.clone(),
-                    position: login.position,
-                    crew: login.crew.iter().map(|crew| crew.name.clone()).collect(),
-                    drones: login.drones,
+            let db = database.lock().unwrap();
+            let before = match db.find_police_station().into_iter().find(|station| station.id == login.id) {
+                Some(station) => station,
+                None => return result(&mut HttpResponse::NotFound(), "Station not found !")
+            };
+            if let Some(position) = login.position {
+                if let Err(err) = roads.lock().unwrap().check_station(Coordinates::from(position), config.dispatch.min_reach) {
+                    return result(&mut HttpResponse::BadRequest(), &err);
                 }
-            );
-            return HttpResponse::Ok().content_type("application/json").body("{\"result\": \"success\"}");
+            }
+            let after = PoliceStation {
+                name: login.name.clone().unwrap_or_else(|| before.name.clone()),
+                position: login.position.unwrap_or(before.position),
+                ..before.clone()
+            };
+            if after.name.trim().is_empty() {
+                return result(&mut HttpResponse::BadRequest(), "Name must not be empty !");
+            }
+            if db.update_police_station(&after.id, &after.name, after.position) {
+                Auditor::new(&i, ip).record(&db, "update", "station", &after.id, Some(&before), Some(&after));
+                dispatcher.do_send(StationChanged(after.id));
+            }
+            return result(&mut HttpResponse::Ok(), "success");
         }
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
 }
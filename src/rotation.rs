@@ -0,0 +1,64 @@
+use actix_web::dev::{Service, Transform, ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use actix_web::error::InternalError;
+use futures::future::{ok, Either, FutureResult};
+use futures::Poll;
+use std::sync::{Arc, Mutex};
+use crate::database::DatabaseAccess;
+
+// endpoints a user with a pending password change may still reach
+const ALLOWED_PATHS: [&str; 5] = ["/", "/user/login", "/user/logout", "/user/type", "/user/passwd"];
+
+/// Refuses every authenticated request from users flagged `must_change`
+/// (e.g. the bootstrap admin) until their password has been rotated.
+pub struct RequirePasswordRotation(pub Arc<Mutex<DatabaseAccess>>);
+
+impl<S, B> Transform<S> for RequirePasswordRotation
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequirePasswordRotationMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequirePasswordRotationMiddleware { service, database: self.0.clone() })
+    }
+}
+
+pub struct RequirePasswordRotationMiddleware<S> {
+    service: S,
+    database: Arc<Mutex<DatabaseAccess>>,
+}
+
+impl<S, B> Service for RequirePasswordRotationMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let exempt = ALLOWED_PATHS.contains(&req.path()) || req.path().starts_with("/static/");
+        let blocked = !exempt && req.cookie("sess")
+            .is_some_and(|token| self.database.lock().unwrap().password_change_required(token.value()));
+        if blocked {
+            let response = HttpResponse::Forbidden().content_type("application/json")
+                .body("{\"result\": \"Password change required !\"}");
+            Either::B(ok(req.error_response(InternalError::from_response("password change required", response))))
+        } else {
+            Either::A(self.service.call(req))
+        }
+    }
+}
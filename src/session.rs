@@ -0,0 +1,29 @@
+use actix::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::database::DatabaseAccess;
+use log::debug;
+
+// how often expired sessions are purged from login_data
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct SessionSweeper(Arc<Mutex<DatabaseAccess>>);
+
+impl SessionSweeper {
+    pub fn new(db: Arc<Mutex<DatabaseAccess>>) -> Self {
+        SessionSweeper(db)
+    }
+}
+
+impl Actor for SessionSweeper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SWEEP_INTERVAL, |act, _ctx| {
+            let swept = act.0.lock().unwrap().sweep_sessions();
+            if swept > 0 {
+                debug!("swept {} expired session(s)", swept);
+            }
+        });
+    }
+}
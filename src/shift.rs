@@ -0,0 +1,171 @@
+use actix::Addr;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Data, Json};
+use log::error;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use crate::audit::{self, Auditor};
+use crate::crew::ClockTime;
+use crate::database::{DatabaseAccess, Shift};
+use crate::dispatcher::{DispatcherService, StationChanged};
+use crate::result;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const MINUTES_PER_WEEK: i64 = 7 * MINUTES_PER_DAY;
+
+#[derive(Deserialize)]
+pub struct ScheduleInfo {
+    station: String,
+    shifts: Vec<Shift>,
+}
+
+#[derive(Deserialize)]
+pub struct ListInfo {
+    station: Option<String>,
+}
+
+// seconds since monday 00:00 local time; the epoch was a thursday
+fn second_of_week(now: i64, utc_offset: i32) -> i64 {
+    (now + i64::from(utc_offset) * 60 + 3 * MINUTES_PER_DAY * 60).rem_euclid(MINUTES_PER_WEEK * 60)
+}
+
+// start and end as minutes since monday 00:00, a shift ending at or before its start runs into the next day
+fn week_range(shift: &Shift) -> (i64, i64) {
+    let start = i64::from(shift.weekday - 1) * MINUTES_PER_DAY + i64::from(shift.start.minutes());
+    let mut length = i64::from(shift.end.minutes() - shift.start.minutes());
+    if length <= 0 {
+        length += MINUTES_PER_DAY;
+    }
+    (start, start + length)
+}
+
+fn covers(shift: &Shift, second: i64) -> bool {
+    let (start, end) = week_range(shift);
+    let minute = second / 60;
+    // shifts running past sunday midnight continue at the start of the week
+    (start..end).contains(&minute) || (start..end).contains(&(minute + MINUTES_PER_WEEK))
+}
+
+/// Officers and drones a station can send at `now`: those of the shifts it is in, at most
+/// as many as its crew and drones on duty, or, for a station without a schedule, all on duty.
+pub fn capacity(station: &str, shifts: &[Shift], on_duty: (usize, usize), now: i64, utc_offset: i32) -> (usize, usize) {
+    let second = second_of_week(now, utc_offset);
+    let mut scheduled = shifts.iter().filter(|shift| shift.station == station).peekable();
+    if scheduled.peek().is_none() {
+        return on_duty;
+    }
+    let (crew, drones) = scheduled.filter(|shift| covers(shift, second))
+        .fold((0, 0), |(crew, drones), shift| (crew + shift.crew.max(0) as usize, drones + shift.drones.max(0) as usize));
+    (crew.min(on_duty.0), drones.min(on_duty.1))
+}
+
+/// Seconds from `now` to the next start or end of any shift.
+pub fn next_boundary(shifts: &[Shift], now: i64, utc_offset: i32) -> Option<i64> {
+    let second = second_of_week(now, utc_offset);
+    shifts.iter()
+        .flat_map(|shift| {
+            let (start, end) = week_range(shift);
+            vec![start, end]
+        })
+        .map(|minute| (minute * 60 - second).rem_euclid(MINUTES_PER_WEEK * 60))
+        .map(|wait| if wait == 0 { MINUTES_PER_WEEK * 60 } else { wait })
+        .min()
+}
+
+/// Minutes since local midnight at `now`, the clock of crew and equipment shifts.
+pub fn minute_of_day(now: i64, utc_offset: i32) -> i32 {
+    (second_of_week(now, utc_offset) / 60 % MINUTES_PER_DAY) as i32
+}
+
+/// Seconds from `now` to the next of the daily `times` crew or equipment come or go.
+pub fn next_daily_boundary(times: &[ClockTime], now: i64, utc_offset: i32) -> Option<i64> {
+    let second = second_of_week(now, utc_offset) % (MINUTES_PER_DAY * 60);
+    times.iter()
+        .map(|time| (i64::from(time.minutes()) * 60 - second).rem_euclid(MINUTES_PER_DAY * 60))
+        .map(|wait| if wait == 0 { MINUTES_PER_DAY * 60 } else { wait })
+        .min()
+}
+
+fn validate(shift: &Shift) -> Result<(), &'static str> {
+    if !(1..=7).contains(&shift.weekday) {
+        return Err("Weekday must be 1 (monday) to 7 (sunday) !");
+    }
+    if shift.crew < 0 || shift.drones < 0 {
+        return Err("Crew and drones must not be negative !");
+    }
+    Ok(())
+}
+
+pub fn list_shifts(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<ListInfo>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(_i) = info {
+        return HttpResponse::Ok().json(database.lock().unwrap().find_shifts(login.station.as_deref()));
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+/// `/ps/shifts`: replaces the weekly schedule of a station, none removes it.
+pub fn set_shifts(database: Data<Arc<Mutex<DatabaseAccess>>>, dispatcher: Data<Addr<DispatcherService>>, login: Json<ScheduleInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            if let Some(err) = login.shifts.iter().find_map(|shift| validate(shift).err()) {
+                return result(&mut HttpResponse::BadRequest(), err);
+            }
+            let db = database.lock().unwrap();
+            if !db.find_police_station().iter().any(|ps| ps.id == login.station) {
+                return result(&mut HttpResponse::NotFound(), "Station not found !");
+            }
+            let shifts = login.shifts.iter()
+                .map(|shift| Shift { station: login.station.clone(), ..shift.clone() })
+                .collect::<Vec<_>>();
+            let before = db.find_shifts(Some(&login.station));
+            return match db.replace_shifts(&login.station, &shifts) {
+                Ok(()) => {
+                    Auditor::new(&i, ip).record(&db, "update", "shifts", &login.station, Some(&before), Some(&shifts));
+                    dispatcher.do_send(StationChanged(login.station.clone()));
+                    result(&mut HttpResponse::Ok(), "success")
+                }
+                Err(err) => {
+                    error!("failed to store the shifts of station {}: {}", login.station, err);
+                    result(&mut HttpResponse::InternalServerError(), "failed")
+                }
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+#[test]
+fn test_shift_capacity() {
+    let shift = |weekday, start: &str, end: &str, crew| Shift {
+        station: "ps1".to_string(),
+        weekday,
+        start: ClockTime::parse(start).unwrap(),
+        end: ClockTime::parse(end).unwrap(),
+        crew,
+        drones: 1,
+    };
+    // monday day shift and a sunday night shift running into monday
+    let shifts = vec![shift(1, "08:00", "20:00", 4), shift(7, "20:00", "08:00", 2)];
+    // 1970-01-05 was a monday
+    let monday = 4 * 24 * 3600;
+    assert_eq!(capacity("ps1", &shifts, (9, 3), monday + 3600, 0), (2, 1));
+    assert_eq!(capacity("ps1", &shifts, (9, 3), monday + 9 * 3600, 0), (4, 1));
+    assert_eq!(capacity("ps1", &shifts, (9, 3), monday + 21 * 3600, 0), (0, 0));
+    // 07:00 utc is 15:00 at +08:00
+    assert_eq!(capacity("ps1", &shifts, (9, 3), monday + 7 * 3600, 8 * 60), (4, 1));
+    // no more than the crew and drones on duty
+    assert_eq!(capacity("ps1", &shifts, (3, 0), monday + 9 * 3600, 0), (3, 0));
+    assert_eq!(capacity("ps2", &shifts, (9, 3), monday, 0), (9, 3));
+    assert_eq!(next_boundary(&shifts, monday + 3600, 0), Some(7 * 3600));
+    assert_eq!(next_boundary(&shifts, monday + 8 * 3600, 0), Some(12 * 3600));
+    assert_eq!(next_boundary(&[], monday, 0), None);
+    // crew and equipment shifts repeat every day
+    assert_eq!(minute_of_day(monday + 7 * 3600 + 120, 8 * 60), 15 * 60 + 2);
+    let times = [ClockTime::parse("08:00").unwrap(), ClockTime::parse("16:00").unwrap()];
+    assert_eq!(next_daily_boundary(&times, monday + 17 * 3600, 0), Some(15 * 3600));
+    assert_eq!(next_daily_boundary(&times, monday + 8 * 3600, 0), Some(8 * 3600));
+    assert_eq!(next_daily_boundary(&[], monday, 0), None);
+}
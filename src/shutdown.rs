@@ -0,0 +1,52 @@
+use actix::{Addr, System};
+use actix_web::dev::Server;
+use futures::future::join_all;
+use futures::{Future, Stream};
+use log::{info, warn, error};
+use std::time::Duration;
+use crate::dispatcher::{DispatcherService, Shutdown};
+
+// SIGINT everywhere, plus SIGTERM on unix
+fn signals() -> Box<dyn Stream<Item=&'static str, Error=std::io::Error>> {
+    let interrupt = tokio_signal::ctrl_c().flatten_stream().map(|_| "SIGINT");
+    #[cfg(unix)]
+    {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        let terminate = Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM");
+        Box::new(interrupt.select(terminate))
+    }
+    #[cfg(not(unix))]
+    {
+        Box::new(interrupt)
+    }
+}
+
+/// Stops the system on SIGINT / SIGTERM: the http servers stop accepting and finish
+/// their requests, then the dispatcher works off its mailbox and saves its state.
+/// Both steps get `timeout`; a second signal exits right away.
+pub fn on_signal(servers: Vec<Server>, dispatcher: Addr<DispatcherService>, timeout: Duration) {
+    actix::spawn(signals().into_future()
+        .map_err(|(err, _)| error!("unable to listen for signals: {}", err))
+        .and_then(move |(signal, rest)| {
+            info!("{} received, shutting down", signal.unwrap_or("signal"));
+            actix::spawn(rest.into_future().then(|_| {
+                warn!("second signal received, exiting without saving the dispatcher state");
+                std::process::exit(1);
+                #[allow(unreachable_code)]
+                Ok(())
+            }));
+            join_all(servers.iter().map(|server| server.stop(true)).collect::<Vec<_>>())
+        })
+        .and_then(move |_| {
+            info!("http servers stopped, draining the dispatcher");
+            dispatcher.send(Shutdown).timeout(timeout).then(move |result| {
+                match result {
+                    Ok(Ok(())) => info!("dispatcher drained"),
+                    Ok(Err(())) => error!("dispatcher state could not be saved"),
+                    Err(_) => error!("dispatcher did not drain within {}s, its state is lost", timeout.as_secs()),
+                }
+                System::current().stop();
+                Ok(())
+            })
+        }));
+}
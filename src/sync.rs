@@ -0,0 +1,94 @@
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use actix_web::web::{Data, Query};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::database::{Change, DatabaseAccess, IncidentRoutes, OperatorMark, PoliceStation};
+use crate::result;
+use log::{debug, error};
+
+// how often superseded and expired entries are trimmed from the change log
+const TRIM_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    #[serde(default)]
+    since: i64,
+}
+
+#[derive(Serialize)]
+pub struct Delta<T> {
+    upserted: Vec<T>,
+    deleted: Vec<String>,
+}
+
+impl<T: DeserializeOwned> Delta<T> {
+    fn collect<'a>(changes: impl Iterator<Item=&'a Change>) -> Self {
+        let mut delta = Delta { upserted: vec![], deleted: vec![] };
+        for change in changes {
+            match change.data.as_ref().and_then(|data| serde_json::from_str(data).ok()) {
+                Some(entity) => delta.upserted.push(entity),
+                None => delta.deleted.push(change.entity_id.clone())
+            }
+        }
+        delta
+    }
+}
+
+#[derive(Serialize)]
+pub struct SyncResult {
+    marks: Delta<OperatorMark>,
+    stations: Delta<PoliceStation>,
+    routes: Delta<IncidentRoutes>,
+    // the cursor was unknown (e.g. from another database), so this is the full state
+    reset: bool,
+    // pass back as `since` on the next call
+    next: i64,
+}
+
+/// `/sync?since=<cursor>`: marks, stations and routes changed after the cursor,
+/// each reduced to its latest state. `since=0` returns everything.
+pub fn sync(database: Data<Arc<Mutex<DatabaseAccess>>>, query: Query<SyncQuery>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if info.is_some() {
+        let database = database.lock().unwrap();
+        // a cursor ahead of the log, or behind a trimmed deletion, cannot be resumed
+        let reset = query.since > database.last_change() || (query.since > 0 && query.since < database.change_horizon());
+        let since = if reset { 0 } else { query.since };
+        let changes = database.find_changes(since);
+        let next = changes.last().map(|c| c.seq).unwrap_or(since);
+        let of = |entity: &'static str| changes.iter().filter(move |c| c.entity == entity);
+        return HttpResponse::Ok().json(SyncResult {
+            marks: Delta::collect(of("mark")),
+            stations: Delta::collect(of("station")),
+            routes: Delta::collect(of("route")),
+            reset,
+            next,
+        });
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub struct ChangeLogTrimmer(Arc<Mutex<DatabaseAccess>>);
+
+impl ChangeLogTrimmer {
+    pub fn new(db: Arc<Mutex<DatabaseAccess>>) -> Self {
+        ChangeLogTrimmer(db)
+    }
+}
+
+impl Actor for ChangeLogTrimmer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(TRIM_INTERVAL, |act, _ctx| {
+            match act.0.lock().unwrap().trim_change_log() {
+                Ok(trimmed) if trimmed > 0 => debug!("trimmed {} change log entries", trimmed),
+                Ok(_) => {}
+                Err(err) => error!("unable to trim the change log: {}", err),
+            }
+        });
+    }
+}
@@ -0,0 +1,83 @@
+use rustls::{NoClientAuth, ServerConfig};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use actix_web::{HttpRequest, HttpResponse};
+
+/// Builds the rustls server configuration from a PEM certificate chain and
+/// a PKCS#8 or RSA private key.
+pub fn load_rustls_config(cert: &Path, key: &Path) -> Result<ServerConfig, String> {
+    let open = |path: &Path| File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("{}: {}", path.display(), err));
+    let chain = certs(&mut open(cert)?)
+        .map_err(|_| format!("{}: malformed certificate", cert.display()))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificate found", cert.display()));
+    }
+    let mut keys = pkcs8_private_keys(&mut open(key)?)
+        .map_err(|_| format!("{}: malformed private key", key.display()))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key)?)
+            .map_err(|_| format!("{}: malformed private key", key.display()))?;
+    }
+    if keys.is_empty() {
+        return Err(format!("{}: no private key found", key.display()));
+    }
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, keys.remove(0))
+        .map_err(|err| format!("{}: {}", key.display(), err))?;
+    Ok(config)
+}
+
+pub fn hsts_header(max_age: u64) -> String {
+    format!("max-age={}; includeSubDomains", max_age)
+}
+
+// the request host with its port (if any) replaced by the https port
+fn https_location(host: &str, port: u16, path: &str) -> String {
+    let hostname = match host.rfind(':') {
+        Some(i) if !host.ends_with(']') => &host[..i],
+        _ => host
+    };
+    if port == 443 {
+        format!("https://{}{}", hostname, path)
+    } else {
+        format!("https://{}:{}{}", hostname, port, path)
+    }
+}
+
+/// Handler of the plain http listener, sending every request over to https.
+pub fn redirect_to_https(port: u16) -> impl Fn(HttpRequest) -> HttpResponse + Clone + 'static {
+    move |request: HttpRequest| {
+        let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let location = https_location(request.connection_info().host(), port, path);
+        HttpResponse::PermanentRedirect().header("Location", location).finish()
+    }
+}
+
+#[test]
+fn test_tls_self_signed() {
+    use std::process::Command;
+
+    let dir = std::env::temp_dir().join(format!("dataearth-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let generated = Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=localhost"])
+        .arg("-keyout").arg(&key)
+        .arg("-out").arg(&cert)
+        .output()
+        .expect("openssl is required to generate the test certificate");
+    assert!(generated.status.success(), "{}", String::from_utf8_lossy(&generated.stderr));
+    assert!(load_rustls_config(&cert, &key).is_ok());
+    // swapped files must be rejected rather than served
+    assert!(load_rustls_config(&key, &cert).is_err());
+    assert!(load_rustls_config(&cert, &dir.join("missing.pem")).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(https_location("example.org:80", 443, "/user/type?x=1"), "https://example.org/user/type?x=1");
+    assert_eq!(https_location("example.org", 8443, "/"), "https://example.org:8443/");
+    assert_eq!(https_location("[::1]", 443, "/"), "https://[::1]/");
+}
@@ -3,6 +3,11 @@ use std::sync::{Mutex, Arc};
 use actix_web::web::{Data, Json};
 use serde::Deserialize;
 use actix_web::{HttpRequest, Responder, HttpResponse, HttpMessage};
+use postgres::error::UNIQUE_VIOLATION;
+use log::error;
+use serde_json::json;
+use crate::audit::{self, Auditor};
+use crate::result;
 
 #[derive(Deserialize)]
 pub struct DeleteUserInfo {
@@ -16,41 +21,208 @@ pub struct AddUserInfo {
     password: String,
 }
 
+#[derive(Deserialize)]
+pub struct GetUserInfo {
+    username: String
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserInfo {
+    username: String,
+    usertype: i32,
+}
+
+#[derive(Deserialize)]
+pub struct DisableUserInfo {
+    username: String,
+    disabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordInfo {
+    // defaults to the logged in user
+    username: Option<String>,
+    old_password: Option<String>,
+    password: String,
+}
+
+// what the audit log keeps of a user, never the password
+fn audited(user: &User) -> serde_json::Value {
+    json!({"username": user.username, "type": user.user_type, "disabled": user.disabled, "must_change": user.must_change})
+}
+
+fn last_admin() -> HttpResponse {
+    result(&mut HttpResponse::Conflict(), "Cannot remove the last administrator !")
+}
+
 pub fn delete_user(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<DeleteUserInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         if i.user_type == 1 {
-            database.lock().unwrap().delete_user(login.username.clone());
-            return HttpResponse::Ok().content_type("application/json").body("{result: \"success\"}");
+            let db = database.lock().unwrap();
+            if db.count_other_admins(&login.username) == 0 {
+                return last_admin();
+            }
+            let before = db.find_user(login.username.clone()).map(|user| audited(&user));
+            if db.delete_user(login.username.clone()) && before.is_some() {
+                Auditor::new(&i, ip).record(&db, "delete", "user", &login.username, before.as_ref(), None);
+            }
+            return result(&mut HttpResponse::Ok(), "success");
         }
     }
-    HttpResponse::Ok().content_type("application/json").body("{result: \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
 pub fn add_user(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<AddUserInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
     let info = crate::login::get_login(database.clone(), request);
     if let Some(i) = info {
         if i.user_type == 1 {
-            database.lock().unwrap().add_user(User {
+            let db = database.lock().unwrap();
+            let user = User {
                 username: login.username.clone(),
                 user_type: login.usertype,
                 passwd: login.password.clone(),
-            });
-            return HttpResponse::Ok().content_type("application/json").body("{\"result\": \"success\"}");
+                disabled: false,
+                must_change: false,
+            };
+            return match db.add_user(user.clone()) {
+                Ok(_) => {
+                    Auditor::new(&i, ip).record(&db, "create", "user", &user.username, None, Some(&audited(&user)));
+                    result(&mut HttpResponse::Ok(), "success")
+                }
+                Err(ref err) if err.code() == Some(&UNIQUE_VIOLATION) =>
+                    result(&mut HttpResponse::Conflict(), "Username already exists !"),
+                Err(err) => {
+                    error!("failed to add user {}: {}", login.username, err);
+                    result(&mut HttpResponse::InternalServerError(), "failed")
+                }
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn list_users(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            return HttpResponse::Ok().content_type("application/json").body(
+                serde_json::to_string(&database.lock().unwrap().find_users()).unwrap()
+            );
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn get_user(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<GetUserInfo>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 || i.username == login.username {
+            let user = database.lock().unwrap().find_users().into_iter()
+                .find(|u| u.username == login.username);
+            return match user {
+                Some(user) => HttpResponse::Ok().content_type("application/json")
+                    .body(serde_json::to_string(&user).unwrap()),
+                None => result(&mut HttpResponse::NotFound(), "User not found !")
+            };
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn update_user(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<UpdateUserInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let db = database.lock().unwrap();
+            if login.usertype != 1 && db.count_other_admins(&login.username) == 0 {
+                return last_admin();
+            }
+            let before = db.find_user(login.username.clone()).map(|user| audited(&user));
+            if db.update_user_type(&login.username, login.usertype) {
+                let after = db.find_user(login.username.clone()).map(|user| audited(&user));
+                Auditor::new(&i, ip).record(&db, "update", "user", &login.username, before.as_ref(), after.as_ref());
+                return result(&mut HttpResponse::Ok(), "success");
+            }
+            return result(&mut HttpResponse::NotFound(), "User not found !");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn disable_user(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<DisableUserInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            let db = database.lock().unwrap();
+            if login.disabled && db.count_other_admins(&login.username) == 0 {
+                return last_admin();
+            }
+            let before = db.find_user(login.username.clone()).map(|user| audited(&user));
+            if db.set_user_disabled(&login.username, login.disabled) {
+                let after = db.find_user(login.username.clone()).map(|user| audited(&user));
+                let action = if login.disabled { "disable" } else { "enable" };
+                Auditor::new(&i, ip).record(&db, action, "user", &login.username, before.as_ref(), after.as_ref());
+                return result(&mut HttpResponse::Ok(), "success");
+            }
+            return result(&mut HttpResponse::NotFound(), "User not found !");
+        }
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+// users may change their own password given the old one, admins may reset anyone's
+pub fn change_password(database: Data<Arc<Mutex<DatabaseAccess>>>, login: Json<ChangePasswordInfo>, request: HttpRequest) -> impl Responder {
+    let ip = audit::client_ip(&request);
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        let username = login.username.clone().unwrap_or_else(|| i.username.clone());
+        let db = database.lock().unwrap();
+        let permitted = if username == i.username {
+            db.find_user(username.clone())
+                .is_some_and(|user| Some(&user.passwd) == login.old_password.as_ref())
+        } else {
+            i.user_type == 1
+        };
+        if !permitted {
+            return result(&mut HttpResponse::Forbidden(), "Password is wrong !");
+        }
+        // a password reset by an administrator is only temporary
+        if db.change_password(&username, &login.password, username != i.username) {
+            let action = if username != i.username { "reset_password" } else { "change_password" };
+            Auditor::new(&i, ip).record::<()>(&db, action, "user", &username, None, None);
+            return result(&mut HttpResponse::Ok(), "success");
+        }
+        return result(&mut HttpResponse::NotFound(), "User not found !");
+    }
+    result(&mut HttpResponse::Ok(), "failed")
+}
+
+pub fn list_sessions(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
+    let info = crate::login::get_login(database.clone(), request);
+    if let Some(i) = info {
+        if i.user_type == 1 {
+            return HttpResponse::Ok().content_type("application/json").body(
+                serde_json::to_string(&database.lock().unwrap().find_sessions()).unwrap()
+            );
         }
     }
-    HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+    result(&mut HttpResponse::Ok(), "failed")
 }
 
 pub fn logout(database: Data<Arc<Mutex<DatabaseAccess>>>, request: HttpRequest) -> impl Responder {
     let cookie = request.cookie("sess");
     match cookie {
         None => {
-            HttpResponse::Ok().content_type("application/json").body("{\"result\": \"failed\"}")
+            result(&mut HttpResponse::Ok(), "failed")
         }
         Some(token) => {
             database.lock().unwrap().logout(token.value().to_string());
-            return HttpResponse::Ok().content_type("application/json").body("{\"result\": \"success\"}");
+            result(&mut HttpResponse::Ok(), "success")
         }
     }
 }
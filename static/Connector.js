@@ -29,3 +29,33 @@ Connector = function(opts){
     });
 };
 
+/**
+ * 读取登录时下发的csrf cookie，所有修改状态的请求都需要通过请求头回传
+ * @returns {string}
+ */
+csrfToken = function(){
+    var match = document.cookie.match(/(?:^|;\s*)csrf=([^;]*)/);
+    return match ? decodeURIComponent(match[1]) : "";
+};
+
+$.ajaxSetup({
+    beforeSend:function(xhr){
+        xhr.setRequestHeader("X-CSRF-Token", csrfToken());
+    }
+});
+
+
+/**
+ * 订阅服务器推送的事件(/events)，断线重连时浏览器会携带Last-Event-ID从断点继续
+ * @param {type} handlers 事件类型到处理函数的映射，如 {incident_created: function(event){}}
+ * @returns {EventSource}
+ */
+Subscribe = function(handlers){
+    var source = new EventSource("/events");
+    $.each(handlers, function(type, handler){
+        source.addEventListener(type, function(message){
+            handler(JSON.parse(message.data));
+        });
+    });
+    return source;
+};